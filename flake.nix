@@ -75,22 +75,44 @@
               nginx-keycloak = {
                 wantedBy = ["multi-user.target"];
                 serviceConfig = {
+                  Type = "notify";
+                  ExecStart = "${self.packages.${system}.nginx-keycloak}/bin/nginx-keycloak";
+                  ExecReload = "${pkgs.coreutils}/bin/kill -HUP $MAINPID";
+                  TimeoutStopSec = 45;
                   User = "nginx-keycloak";
                   Group = "nginx-keycloak";
                   DynamicUser = true;
                   SupplementaryGroups = mkIf cfg.redis "redis-nginx-keycloak";
                   LoadCredential = ["client_secret:${cfg.settings.client_secret_file}"];
+
+                  CapabilityBoundingSet = "";
+                  LockPersonality = true;
+                  MemoryDenyWriteExecute = true;
+                  NoNewPrivileges = true;
+                  PrivateDevices = true;
+                  PrivateTmp = true;
+                  ProtectClock = true;
+                  ProtectControlGroups = true;
+                  ProtectHome = true;
+                  ProtectHostname = true;
+                  ProtectKernelLogs = true;
+                  ProtectKernelModules = true;
+                  ProtectKernelTunables = true;
+                  ProtectSystem = "strict";
+                  RestrictAddressFamilies = ["AF_INET" "AF_INET6" "AF_UNIX"];
+                  RestrictNamespaces = true;
+                  RestrictRealtime = true;
+                  SystemCallArchitectures = "native";
+                  SystemCallFilter = ["@system-service" "~@privileged"];
                 };
                 environment = {
-                  CONFIG_PATH = pkgs.writeText "config.json" (builtins.toJSON (cfg.settings));
+                  # the client secret is read from $CREDENTIALS_DIRECTORY/client_secret
+                  CONFIG_PATH = pkgs.writeText "config.json" (builtins.toJSON (removeAttrs cfg.settings ["client_secret_file"]));
                   RUST_LOG =
                     if cfg.debug
                     then "debug"
                     else "info";
                 };
-                script = ''
-                  CLIENT_SECRET_FILE=$CREDENTIALS_DIRECTORY/client_secret ${self.packages.${system}.nginx-keycloak}/bin/nginx-keycloak
-                '';
               };
             };
           };
@@ -114,6 +136,8 @@
           proxy_pass_request_body off;
           proxy_set_header Content-Length "";
           proxy_set_header X-Request-Uri $scheme://$host$request_uri;
+          proxy_set_header X-Original-Method $request_method;
+          proxy_set_header X-Real-IP $remote_addr;
         '';
       };
     };
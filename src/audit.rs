@@ -0,0 +1,377 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use eyre::{eyre, Result};
+use redis::aio::MultiplexedConnection;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::{info, warn};
+
+use crate::{client::ClientInfo, config::Config, metrics};
+
+const CHANNEL_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[cfg_attr(test, derive(PartialEq, Eq))]
+#[serde(rename_all = "lowercase")]
+pub enum AuditSink {
+    Stdout,
+    File,
+    Redis,
+}
+
+/// Structured record of an authorization decision or a completed login.
+#[derive(Debug, Serialize)]
+pub struct Event {
+    /// Unix timestamp of the event.
+    pub timestamp: u64,
+    #[serde(rename = "type")]
+    pub kind: EventKind,
+    pub request_id: String,
+    pub sub: Option<String>,
+    pub username: Option<String>,
+    pub role: String,
+    pub host: Option<String>,
+    pub path: String,
+    pub decision: &'static str,
+    pub cache_hit: bool,
+    pub client_ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventKind {
+    Authorization,
+    Login,
+}
+
+/// Writes audit events to the configured sink in the background.
+pub struct AuditLog {
+    sender: mpsc::Sender<Event>,
+    /// Number of events dropped since the queue last accepted one.
+    dropped: AtomicU64,
+}
+
+impl AuditLog {
+    /// Start the audit log writer, if an audit log sink has been configured.
+    pub fn start(config: &Config) -> Result<Option<Self>> {
+        let Some(sink) = config.audit_log else {
+            return Ok(None);
+        };
+        let (sender, receiver) = mpsc::channel(CHANNEL_CAPACITY);
+        match sink {
+            AuditSink::Stdout => {
+                std::thread::spawn(move || write_lines(receiver, io::stdout()));
+            }
+            AuditSink::File => {
+                let path = config
+                    .audit_log_file
+                    .as_ref()
+                    .ok_or_else(|| eyre!("AUDIT_LOG_FILE is required for the file audit log"))?;
+                let file = RotatingFile::open(
+                    path.clone(),
+                    config.audit_log_max_size,
+                    config.audit_log_max_files,
+                )?;
+                std::thread::spawn(move || write_lines(receiver, file));
+            }
+            AuditSink::Redis => {
+                let client = redis::Client::open(config.redis_url.expose())?;
+                tokio::spawn(write_stream(
+                    receiver,
+                    client,
+                    config.audit_log_stream.clone(),
+                    config.audit_log_stream_max_len,
+                ));
+            }
+        }
+        Ok(Some(Self {
+            sender,
+            dropped: AtomicU64::new(0),
+        }))
+    }
+
+    /// Queue an event. Never waits for the writer: if it cannot keep up (e.g. because the sink is
+    /// unavailable) and the queue is full, the event is dropped and counted instead.
+    pub fn record(&self, event: Event) {
+        match self.sender.try_send(event) {
+            Ok(()) => {
+                let dropped = self.dropped.swap(0, Ordering::Relaxed);
+                if dropped > 0 {
+                    info!("audit log writer has caught up, {dropped} events have been dropped");
+                }
+            }
+            Err(TrySendError::Full(_)) => {
+                metrics::audit_event_dropped();
+                if self.dropped.fetch_add(1, Ordering::Relaxed) == 0 {
+                    warn!("audit log writer cannot keep up, dropping events");
+                }
+            }
+            Err(TrySendError::Closed(_)) => {
+                metrics::audit_event_dropped();
+                warn!("audit log writer has stopped, event has been dropped");
+            }
+        }
+    }
+}
+
+fn write_lines(mut receiver: mpsc::Receiver<Event>, mut writer: impl Write) {
+    while let Some(event) = receiver.blocking_recv() {
+        let result = serde_json::to_vec(&event)
+            .map_err(io::Error::from)
+            .and_then(|mut line| {
+                line.push(b'\n');
+                writer.write_all(&line)?;
+                writer.flush()
+            });
+        if let Err(err) = result {
+            warn!("could not write audit event: {err}");
+        }
+    }
+}
+
+async fn write_stream(
+    mut receiver: mpsc::Receiver<Event>,
+    client: redis::Client,
+    stream: String,
+    max_len: usize,
+) {
+    let mut con: Option<MultiplexedConnection> = None;
+    while let Some(event) = receiver.recv().await {
+        // retry until the event has been written, so that no events are lost during an outage
+        loop {
+            let result = async {
+                let con = match &mut con {
+                    Some(con) => con,
+                    None => con.insert(client.get_multiplexed_tokio_connection().await?),
+                };
+                redis::cmd("XADD")
+                    .arg(&stream)
+                    .arg("MAXLEN")
+                    .arg("~")
+                    .arg(max_len)
+                    .arg("*")
+                    .arg(event.fields())
+                    .query_async::<_, ()>(con)
+                    .await
+            }
+            .await;
+            match result {
+                Ok(()) => break,
+                Err(err) => {
+                    warn!("could not write audit event to redis: {err}");
+                    con = None;
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+            }
+        }
+    }
+}
+
+impl Event {
+    /// Attach the client the request originates from.
+    #[must_use]
+    pub fn with_client(self, client: &ClientInfo) -> Self {
+        Self {
+            client_ip: client.ip.clone(),
+            user_agent: client.user_agent.clone(),
+            ..self
+        }
+    }
+
+    /// Fields of the event as stored in a redis stream. Missing values are omitted.
+    fn fields(&self) -> Vec<(&'static str, String)> {
+        let kind = match self.kind {
+            EventKind::Authorization => "authorization",
+            EventKind::Login => "login",
+        };
+        [
+            ("timestamp", Some(self.timestamp.to_string())),
+            ("type", Some(kind.to_owned())),
+            ("request_id", Some(self.request_id.clone())),
+            ("sub", self.sub.clone()),
+            ("username", self.username.clone()),
+            ("role", Some(self.role.clone())),
+            ("host", self.host.clone()),
+            ("path", Some(self.path.clone())),
+            ("decision", Some(self.decision.to_owned())),
+            ("cache_hit", Some(self.cache_hit.to_string())),
+            ("client_ip", self.client_ip.clone()),
+            ("user_agent", self.user_agent.clone()),
+        ]
+        .into_iter()
+        .filter_map(|(field, value)| Some((field, value?)))
+        .collect()
+    }
+}
+
+/// Log file that is rotated once it would exceed `max_size` bytes. Rotated files are renamed to
+/// `{path}.1`, `{path}.2`, ... and at most `max_files` of them are kept.
+struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    max_files: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_size: u64, max_files: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            max_size,
+            max_files,
+            file,
+            size,
+        })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for i in (1..self.max_files).rev() {
+                let from = rotated_path(&self.path, i);
+                if from.exists() {
+                    fs::rename(from, rotated_path(&self.path, i + 1))?;
+                }
+            }
+            fs::rename(&self.path, rotated_path(&self.path, 1))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(format!(".{index}"));
+    path.into()
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotating_file() {
+        let dir = std::env::temp_dir().join(format!("nginx-keycloak-audit-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("audit.log");
+
+        let mut file = RotatingFile::open(path.clone(), 10, 2).unwrap();
+        for line in ["aaaaaa\n", "bbbbbb\n", "cccccc\n", "dddddd\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+        assert_eq!(fs::read_to_string(&path).unwrap(), "dddddd\n");
+        assert_eq!(
+            fs::read_to_string(rotated_path(&path, 1)).unwrap(),
+            "cccccc\n"
+        );
+        assert_eq!(
+            fs::read_to_string(rotated_path(&path, 2)).unwrap(),
+            "bbbbbb\n"
+        );
+        assert!(!rotated_path(&path, 3).exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_event_fields() {
+        let event = Event {
+            timestamp: 1_700_000_000,
+            kind: EventKind::Authorization,
+            request_id: "abc".to_owned(),
+            sub: Some("f3b2c1".to_owned()),
+            username: None,
+            role: "admin".to_owned(),
+            host: Some("service.domain.de".to_owned()),
+            path: "/".to_owned(),
+            decision: "ok",
+            cache_hit: true,
+            client_ip: Some("192.0.2.1".to_owned()),
+            user_agent: None,
+        };
+        assert_eq!(
+            event.fields(),
+            [
+                ("timestamp", "1700000000".to_owned()),
+                ("type", "authorization".to_owned()),
+                ("request_id", "abc".to_owned()),
+                ("sub", "f3b2c1".to_owned()),
+                ("role", "admin".to_owned()),
+                ("host", "service.domain.de".to_owned()),
+                ("path", "/".to_owned()),
+                ("decision", "ok".to_owned()),
+                ("cache_hit", "true".to_owned()),
+                ("client_ip", "192.0.2.1".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_record_drops_events_when_full() {
+        let (sender, mut receiver) = mpsc::channel(1);
+        let audit = AuditLog {
+            sender,
+            dropped: AtomicU64::new(0),
+        };
+        let event = |request_id: &str| Event {
+            timestamp: 1_700_000_000,
+            kind: EventKind::Login,
+            request_id: request_id.to_owned(),
+            sub: None,
+            username: None,
+            role: "admin".to_owned(),
+            host: None,
+            path: "/".to_owned(),
+            decision: "login",
+            cache_hit: false,
+            client_ip: None,
+            user_agent: None,
+        };
+
+        audit.record(event("a"));
+        audit.record(event("b"));
+        audit.record(event("c"));
+        assert_eq!(audit.dropped.load(Ordering::Relaxed), 2);
+
+        assert_eq!(receiver.try_recv().unwrap().request_id, "a");
+        audit.record(event("d"));
+        assert_eq!(audit.dropped.load(Ordering::Relaxed), 0);
+        assert_eq!(receiver.try_recv().unwrap().request_id, "d");
+
+        drop(receiver);
+        audit.record(event("e"));
+    }
+}
@@ -0,0 +1,64 @@
+use std::{
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+/// Remembers that a dependency is failing, so that requests which have an alternative (e.g. a
+/// stale decision) can use it immediately instead of each waiting for another timeout.
+pub struct CircuitBreaker {
+    open_until: Mutex<Option<Instant>>,
+    duration: Duration,
+}
+
+impl CircuitBreaker {
+    pub const fn new(duration: Duration) -> Self {
+        Self {
+            open_until: Mutex::new(None),
+            duration,
+        }
+    }
+
+    /// Whether the dependency has failed recently.
+    pub fn is_open(&self) -> bool {
+        self.open_until
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .is_some_and(|open_until| open_until > Instant::now())
+    }
+
+    /// Record a failure, which opens the breaker for the configured duration.
+    pub fn trip(&self) {
+        *self
+            .open_until
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Some(Instant::now() + self.duration);
+    }
+
+    /// Record a success, which closes the breaker again.
+    pub fn reset(&self) {
+        *self
+            .open_until
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = None;
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_circuit_breaker() {
+        let breaker = CircuitBreaker::new(Duration::from_mins(1));
+        assert!(!breaker.is_open());
+        breaker.trip();
+        assert!(breaker.is_open());
+        breaker.reset();
+        assert!(!breaker.is_open());
+
+        let expired = CircuitBreaker::new(Duration::ZERO);
+        expired.trip();
+        assert!(!expired.is_open());
+    }
+}
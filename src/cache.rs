@@ -0,0 +1,207 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+/// Bounded in-memory cache of authorization decisions in front of redis.
+///
+/// Entries are keyed by session key and role. Sessions can be invalidated explicitly, e.g.
+/// when another instance publishes an invalidation via redis.
+pub struct LocalCache<T> {
+    store: Mutex<Store<T>>,
+    capacity: usize,
+    ttl: Duration,
+}
+
+type Key = (String, String);
+
+struct Store<T> {
+    entries: HashMap<Key, Entry<T>>,
+    /// Keys ordered by expiry, so that expired entries and the ones that would expire first can
+    /// be evicted without scanning the whole cache.
+    expiry: BTreeMap<(Instant, u64), Key>,
+    /// Cached roles by session key, so that sessions can be invalidated without scanning the
+    /// whole cache.
+    sessions: HashMap<String, HashSet<String>>,
+    /// Distinguishes entries that expire at the same instant.
+    next_id: u64,
+}
+
+struct Entry<T> {
+    value: T,
+    expires_at: Instant,
+    id: u64,
+}
+
+impl<T> Store<T> {
+    fn remove(&mut self, key: &Key) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.expiry.remove(&(entry.expires_at, entry.id));
+            self.unindex(key);
+        }
+    }
+
+    fn unindex(&mut self, (session_key, role): &Key) {
+        if let Some(roles) = self.sessions.get_mut(session_key) {
+            roles.remove(role);
+            if roles.is_empty() {
+                self.sessions.remove(session_key);
+            }
+        }
+    }
+}
+
+impl<T: Clone> LocalCache<T> {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            store: Mutex::new(Store {
+                entries: HashMap::new(),
+                expiry: BTreeMap::new(),
+                sessions: HashMap::new(),
+                next_id: 0,
+            }),
+            capacity,
+            ttl,
+        }
+    }
+
+    pub fn get(&self, session_key: &str, role: &str) -> Option<T> {
+        let mut store = self.store.lock().unwrap_or_else(PoisonError::into_inner);
+        let key = (session_key.to_owned(), role.to_owned());
+        match store.entries.get(&key) {
+            Some(entry) if entry.expires_at > Instant::now() => Some(entry.value.clone()),
+            Some(_) => {
+                store.remove(&key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Cache a decision for at most `ttl`, or less if the configured ttl of the cache is shorter.
+    pub fn insert(&self, session_key: &str, role: &str, value: T, ttl: Duration) {
+        let now = Instant::now();
+        let key = (session_key.to_owned(), role.to_owned());
+        let mut store = self.store.lock().unwrap_or_else(PoisonError::into_inner);
+        store.remove(&key);
+        // every entry is evicted at most once, so this is cheap on average
+        while let Some((&(expires_at, _), _)) = store.expiry.first_key_value() {
+            if expires_at > now && store.entries.len() < self.capacity {
+                break;
+            }
+            if let Some((_, evicted)) = store.expiry.pop_first() {
+                store.entries.remove(&evicted);
+                store.unindex(&evicted);
+            }
+        }
+        let id = store.next_id;
+        store.next_id += 1;
+        let expires_at = now + ttl.min(self.ttl);
+        store.expiry.insert((expires_at, id), key.clone());
+        store
+            .sessions
+            .entry(key.0.clone())
+            .or_default()
+            .insert(key.1.clone());
+        store.entries.insert(
+            key,
+            Entry {
+                value,
+                expires_at,
+                id,
+            },
+        );
+    }
+
+    pub fn remove(&self, session_key: &str, role: &str) {
+        self.store
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&(session_key.to_owned(), role.to_owned()));
+    }
+
+    /// Remove all cached decisions of a session.
+    pub fn invalidate(&self, session_key: &str) {
+        let mut store = self.store.lock().unwrap_or_else(PoisonError::into_inner);
+        for role in store.sessions.remove(session_key).unwrap_or_default() {
+            store.remove(&(session_key.to_owned(), role));
+        }
+    }
+
+    pub fn clear(&self) {
+        let mut store = self.store.lock().unwrap_or_else(PoisonError::into_inner);
+        store.entries.clear();
+        store.expiry.clear();
+        store.sessions.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_insert() {
+        let cache = LocalCache::new(10, Duration::from_secs(100));
+        assert_eq!(cache.get("session:a", "role"), None);
+        cache.insert("session:a", "role", true, Duration::from_secs(100));
+        cache.insert("session:a", "other", false, Duration::from_secs(100));
+        assert_eq!(cache.get("session:a", "role"), Some(true));
+        assert_eq!(cache.get("session:a", "other"), Some(false));
+        assert_eq!(cache.get("session:b", "role"), None);
+
+        cache.insert("session:b", "role", true, Duration::ZERO);
+        assert_eq!(cache.get("session:b", "role"), None);
+    }
+
+    #[test]
+    fn test_capacity() {
+        let cache = LocalCache::new(2, Duration::from_secs(100));
+        cache.insert("session:a", "role", true, Duration::from_secs(10));
+        cache.insert("session:b", "role", true, Duration::from_secs(20));
+        cache.insert("session:c", "role", true, Duration::from_secs(30));
+        assert_eq!(cache.get("session:a", "role"), None);
+        assert_eq!(cache.get("session:b", "role"), Some(true));
+        assert_eq!(cache.get("session:c", "role"), Some(true));
+
+        // replacing an entry doesn't evict another one
+        cache.insert("session:c", "role", false, Duration::from_secs(5));
+        assert_eq!(cache.get("session:b", "role"), Some(true));
+        assert_eq!(cache.get("session:c", "role"), Some(false));
+
+        // expired entries are evicted first
+        cache.insert("session:d", "role", true, Duration::ZERO);
+        cache.insert("session:e", "role", true, Duration::from_secs(40));
+        assert_eq!(cache.get("session:b", "role"), Some(true));
+        assert_eq!(cache.get("session:c", "role"), None);
+        assert_eq!(cache.get("session:e", "role"), Some(true));
+
+        // evicted entries are removed from the session index
+        assert_eq!(indexed_sessions(&cache), ["session:b", "session:e"]);
+    }
+
+    #[test]
+    fn test_invalidate() {
+        let cache = LocalCache::new(10, Duration::from_secs(100));
+        cache.insert("session:a", "role", true, Duration::from_secs(100));
+        cache.insert("session:a", "other", true, Duration::from_secs(100));
+        cache.insert("session:b", "role", true, Duration::from_secs(100));
+        cache.invalidate("session:a");
+        assert_eq!(cache.get("session:a", "role"), None);
+        assert_eq!(cache.get("session:a", "other"), None);
+        assert_eq!(cache.get("session:b", "role"), Some(true));
+        cache.remove("session:b", "role");
+        assert_eq!(cache.get("session:b", "role"), None);
+
+        assert!(indexed_sessions(&cache).is_empty());
+    }
+
+    fn indexed_sessions(cache: &LocalCache<bool>) -> Vec<String> {
+        let store = cache.store.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut sessions: Vec<_> = store.sessions.keys().cloned().collect();
+        drop(store);
+        sessions.sort();
+        sessions
+    }
+}
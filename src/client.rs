@@ -0,0 +1,181 @@
+use std::net::IpAddr;
+
+use axum::http::HeaderMap;
+use eyre::{ensure, Result, WrapErr};
+use ipnet::IpNet;
+
+use crate::config::Config;
+
+/// The client a request originates from, as forwarded by nginx.
+#[derive(Debug, Default, Clone)]
+#[cfg_attr(test, derive(PartialEq, Eq))]
+pub struct ClientInfo {
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+/// Determines the client of a request and whether it may use a session that has been created
+/// by another client.
+#[derive(Debug, Default)]
+pub struct ClientPolicy {
+    trusted_proxies: Vec<IpNet>,
+    bind_user_agent: bool,
+    bind_ipv4_prefix: Option<u8>,
+    bind_ipv6_prefix: Option<u8>,
+}
+
+impl ClientPolicy {
+    pub fn new(config: &Config) -> Result<Self> {
+        let trusted_proxies = config
+            .trusted_proxies
+            .iter()
+            .map(|proxy| {
+                proxy
+                    .parse()
+                    .or_else(|_| proxy.parse::<IpAddr>().map(IpNet::from))
+                    .wrap_err_with(|| format!("invalid trusted proxy: {proxy}"))
+            })
+            .collect::<Result<_>>()?;
+        ensure!(
+            config.session_bind_ipv4_prefix.unwrap_or(0) <= 32,
+            "SESSION_BIND_IPV4_PREFIX must not be greater than 32"
+        );
+        ensure!(
+            config.session_bind_ipv6_prefix.unwrap_or(0) <= 128,
+            "SESSION_BIND_IPV6_PREFIX must not be greater than 128"
+        );
+        Ok(Self {
+            trusted_proxies,
+            bind_user_agent: config.session_bind_user_agent,
+            bind_ipv4_prefix: config.session_bind_ipv4_prefix,
+            bind_ipv6_prefix: config.session_bind_ipv6_prefix,
+        })
+    }
+
+    /// Extract the client from the headers of an auth request.
+    ///
+    /// `X-Real-IP` is set by nginx and preferred. Otherwise the client is the last address in
+    /// `X-Forwarded-For` that does not belong to a trusted proxy, as anything before it may have
+    /// been made up by the client.
+    pub fn client_info(&self, headers: &HeaderMap) -> ClientInfo {
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|x| x.to_str().ok())
+                .map(str::trim)
+                .filter(|x| !x.is_empty())
+        };
+        ClientInfo {
+            ip: header("x-real-ip")
+                .map(ToOwned::to_owned)
+                .or_else(|| self.forwarded_for(header("x-forwarded-for")?)),
+            user_agent: header("user-agent").map(ToOwned::to_owned),
+        }
+    }
+
+    fn forwarded_for(&self, header: &str) -> Option<String> {
+        let mut client = None;
+        for address in header.split(',').rev() {
+            let ip: IpAddr = address.trim().parse().ok()?;
+            client = Some(ip);
+            if !self.trusted_proxies.iter().any(|proxy| proxy.contains(&ip)) {
+                break;
+            }
+        }
+        client.map(|ip| ip.to_string())
+    }
+
+    /// Check whether `client` may use a session that is bound to `bound`. Sessions without
+    /// information about their client (e.g. from older versions) are not bound.
+    pub fn allows(&self, bound: &ClientInfo, client: &ClientInfo) -> bool {
+        let user_agent_matches = !self.bind_user_agent
+            || bound.user_agent.is_none()
+            || bound.user_agent == client.user_agent;
+        let Some(bound_ip) = bound.ip.as_deref().and_then(|ip| ip.parse().ok()) else {
+            return user_agent_matches;
+        };
+        let prefix = match bound_ip {
+            IpAddr::V4(_) => self.bind_ipv4_prefix,
+            IpAddr::V6(_) => self.bind_ipv6_prefix,
+        };
+        let ip_matches = prefix.is_none_or(|prefix| {
+            let client_ip: Option<IpAddr> = client.ip.as_deref().and_then(|ip| ip.parse().ok());
+            IpNet::new(bound_ip, prefix)
+                .is_ok_and(|network| client_ip.is_some_and(|ip| network.trunc().contains(&ip)))
+        });
+        user_agent_matches && ip_matches
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    fn client(ip: Option<&str>, user_agent: Option<&str>) -> ClientInfo {
+        ClientInfo {
+            ip: ip.map(ToOwned::to_owned),
+            user_agent: user_agent.map(ToOwned::to_owned),
+        }
+    }
+
+    #[test]
+    fn test_client_info() {
+        let policy = ClientPolicy {
+            trusted_proxies: vec!["10.0.0.0/8".parse().unwrap()],
+            ..Default::default()
+        };
+        let info = |headers: &[(&'static str, &'static str)]| {
+            let mut map = HeaderMap::new();
+            for (name, value) in headers {
+                map.append(*name, value.parse().unwrap());
+            }
+            policy.client_info(&map)
+        };
+        assert_eq!(info(&[]), client(None, None));
+        assert_eq!(
+            info(&[("x-real-ip", "1.2.3.4"), ("user-agent", "curl/8.0")]),
+            client(Some("1.2.3.4"), Some("curl/8.0"))
+        );
+        assert_eq!(
+            info(&[("x-real-ip", "1.2.3.4"), ("x-forwarded-for", "5.6.7.8")]),
+            client(Some("1.2.3.4"), None)
+        );
+        assert_eq!(
+            info(&[("x-forwarded-for", "6.6.6.6, 5.6.7.8, 10.0.0.1, 10.0.0.2")]),
+            client(Some("5.6.7.8"), None)
+        );
+        assert_eq!(
+            info(&[("x-forwarded-for", "10.0.0.3, 10.0.0.1")]),
+            client(Some("10.0.0.3"), None)
+        );
+        assert_eq!(
+            info(&[("x-forwarded-for", "5.6.7.8, garbage")]),
+            client(None, None)
+        );
+    }
+
+    #[test]
+    fn test_allows() {
+        let policy = ClientPolicy {
+            bind_user_agent: true,
+            bind_ipv4_prefix: Some(24),
+            bind_ipv6_prefix: Some(64),
+            ..Default::default()
+        };
+        let v4 = client(Some("192.168.1.10"), Some("firefox"));
+        assert!(policy.allows(&v4, &client(Some("192.168.1.99"), Some("firefox"))));
+        assert!(!policy.allows(&v4, &client(Some("192.168.2.10"), Some("firefox"))));
+        assert!(!policy.allows(&v4, &client(Some("192.168.1.10"), Some("curl"))));
+        assert!(!policy.allows(&v4, &client(None, Some("firefox"))));
+        assert!(!policy.allows(&v4, &client(Some("2001:db8::1"), Some("firefox"))));
+        assert!(ClientPolicy::default().allows(&v4, &client(Some("1.1.1.1"), Some("curl"))));
+
+        let v6 = client(Some("2001:db8::1"), None);
+        assert!(policy.allows(&v6, &client(Some("2001:db8::ffff"), Some("curl"))));
+        assert!(!policy.allows(&v6, &client(Some("2001:db9::1"), None)));
+
+        // sessions from older versions are not bound
+        assert!(policy.allows(&ClientInfo::default(), &client(Some("1.1.1.1"), None)));
+    }
+}
@@ -0,0 +1,71 @@
+use std::fmt::Display;
+
+use eyre::{ensure, Result};
+
+use crate::{config, reload};
+
+/// Load the config and check that the identity provider and redis can be used with it.
+pub async fn check_config() -> Result<()> {
+    let mut report = Report::default();
+    // later checks are skipped if the config or the clients could not be loaded
+    checks(&mut report).await;
+    report.finish()
+}
+
+async fn checks(report: &mut Report) -> Option<()> {
+    let config = report.check(
+        format!("load config from {}", config::path()),
+        config::load(),
+    )?;
+    let oidc = report.check(
+        "load secrets and create clients",
+        reload::build_oidc(&config, None),
+    )?;
+    if let Some(mismatches) =
+        report.check("fetch discovery document", oidc.verify_discovery().await)
+    {
+        for mismatch in mismatches {
+            report.warn(mismatch);
+        }
+    }
+    report.check(
+        "authenticate client with client credentials grant",
+        oidc.check_client_credentials().await,
+    );
+    report.check("ping redis", oidc.check_redis().await);
+    Some(())
+}
+
+/// Readable report of the checks, printed while they run.
+#[derive(Default)]
+struct Report {
+    failed: usize,
+    warnings: usize,
+}
+
+impl Report {
+    fn check<T, E: Display>(&mut self, check: impl Display, result: Result<T, E>) -> Option<T> {
+        match result {
+            Ok(value) => {
+                println!("[ok]   {check}");
+                Some(value)
+            }
+            Err(err) => {
+                println!("[fail] {check}: {err:#}");
+                self.failed += 1;
+                None
+            }
+        }
+    }
+
+    fn warn(&mut self, message: impl Display) {
+        println!("[warn] {message}");
+        self.warnings += 1;
+    }
+
+    fn finish(self) -> Result<()> {
+        ensure!(self.failed == 0, "{} check(s) failed", self.failed);
+        println!("all checks passed ({} warning(s))", self.warnings);
+        Ok(())
+    }
+}
@@ -0,0 +1,182 @@
+use eyre::{bail, Result};
+use url::Url;
+
+use super::healthcheck::local_url;
+use crate::{
+    config,
+    endpoints::{COOKIE_HEADER, REDIRECT_HEADER, SUB_HEADER, USER_HEADER},
+};
+
+pub fn gen_nginx(role: Option<&str>, url: Option<Url>, location: &str) -> Result<()> {
+    let config = config::load()?;
+    if role.is_none() && config.policy_file.is_none() {
+        // nginx-keycloak would reject every auth request
+        bail!("either --role or POLICY_FILE is required");
+    }
+    let base_url = match url {
+        Some(url) => url,
+        None => local_url(&config.host, config.port)?,
+    };
+    print!(
+        "{}",
+        snippets(
+            &base_url,
+            role,
+            location,
+            &config.auth_callback_path,
+            &config.auth_logout_path
+        )?
+    );
+    Ok(())
+}
+
+/// nginx config for protecting locations with the given role, or according to the policy if no
+/// role is given.
+fn snippets(
+    base_url: &Url,
+    role: Option<&str>,
+    location: &str,
+    callback_path: &str,
+    logout_path: &str,
+) -> Result<String> {
+    let mut auth_url = base_url.join("auth")?;
+    if let Some(role) = role {
+        auth_url.query_pairs_mut().append_pair("role", role);
+    }
+    let directives = auth_directives(location);
+    Ok(format!(
+        "\
+# internal location that forwards auth requests to nginx-keycloak
+location {location} {{
+    internal;
+    proxy_pass {auth_url};
+    proxy_pass_request_body off;
+    proxy_set_header Content-Length \"\";
+    proxy_set_header X-Request-Uri $scheme://$host$request_uri;
+    proxy_set_header X-Original-Method $request_method;
+    proxy_set_header X-Real-IP $remote_addr;
+}}
+
+# login callback, so that it doesn't need to be covered by a protected location
+location = {callback_path} {{
+{callback}}}
+
+# ends the session and redirects to /
+location = {logout_path} {{
+{callback}}}
+
+# add to every location that should be protected
+{directives}
+# pass the identity of the user to the upstream service
+{identity}",
+        callback = indent(&directives),
+        identity = identity_directives(),
+    ))
+}
+
+fn auth_directives(location: &str) -> String {
+    format!(
+        "\
+auth_request {location};
+auth_request_set $auth_redirect ${redirect};
+auth_request_set $auth_cookie ${cookie};
+error_page 401 =307 $auth_redirect;
+add_header Set-Cookie $auth_cookie always;
+",
+        redirect = upstream_variable(REDIRECT_HEADER),
+        cookie = upstream_variable(COOKIE_HEADER),
+    )
+}
+
+fn identity_directives() -> String {
+    format!(
+        "\
+auth_request_set $auth_user ${user};
+auth_request_set $auth_sub ${sub};
+proxy_set_header {USER_HEADER} $auth_user;
+proxy_set_header {SUB_HEADER} $auth_sub;
+",
+        user = upstream_variable(USER_HEADER),
+        sub = upstream_variable(SUB_HEADER),
+    )
+}
+
+/// Name of the nginx variable that contains the given header of the auth response.
+fn upstream_variable(header: &str) -> String {
+    format!("upstream_http_{}", header.to_lowercase().replace('-', "_"))
+}
+
+fn indent(lines: &str) -> String {
+    lines
+        .lines()
+        .flat_map(|line| ["    ", line, "\n"])
+        .collect()
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snippets() {
+        let base_url = Url::parse("http://127.0.0.1:8000/").unwrap();
+        assert_eq!(
+            snippets(
+                &base_url,
+                Some("my role"),
+                ".auth",
+                "/_auth/callback",
+                "/_auth/logout"
+            )
+            .unwrap(),
+            r#"# internal location that forwards auth requests to nginx-keycloak
+location .auth {
+    internal;
+    proxy_pass http://127.0.0.1:8000/auth?role=my+role;
+    proxy_pass_request_body off;
+    proxy_set_header Content-Length "";
+    proxy_set_header X-Request-Uri $scheme://$host$request_uri;
+    proxy_set_header X-Original-Method $request_method;
+    proxy_set_header X-Real-IP $remote_addr;
+}
+
+# login callback, so that it doesn't need to be covered by a protected location
+location = /_auth/callback {
+    auth_request .auth;
+    auth_request_set $auth_redirect $upstream_http_x_auth_redirect;
+    auth_request_set $auth_cookie $upstream_http_x_auth_cookie;
+    error_page 401 =307 $auth_redirect;
+    add_header Set-Cookie $auth_cookie always;
+}
+
+# ends the session and redirects to /
+location = /_auth/logout {
+    auth_request .auth;
+    auth_request_set $auth_redirect $upstream_http_x_auth_redirect;
+    auth_request_set $auth_cookie $upstream_http_x_auth_cookie;
+    error_page 401 =307 $auth_redirect;
+    add_header Set-Cookie $auth_cookie always;
+}
+
+# add to every location that should be protected
+auth_request .auth;
+auth_request_set $auth_redirect $upstream_http_x_auth_redirect;
+auth_request_set $auth_cookie $upstream_http_x_auth_cookie;
+error_page 401 =307 $auth_redirect;
+add_header Set-Cookie $auth_cookie always;
+
+# pass the identity of the user to the upstream service
+auth_request_set $auth_user $upstream_http_x_auth_user;
+auth_request_set $auth_sub $upstream_http_x_auth_sub;
+proxy_set_header X-Auth-User $auth_user;
+proxy_set_header X-Auth-Sub $auth_sub;
+"#
+        );
+        assert!(
+            snippets(&base_url, None, ".auth", "/_auth/callback", "/_auth/logout")
+                .unwrap()
+                .contains("proxy_pass http://127.0.0.1:8000/auth;\n")
+        );
+    }
+}
@@ -0,0 +1,66 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use eyre::{ensure, Result};
+use url::Url;
+
+use crate::config;
+
+pub async fn healthcheck(url: Option<Url>, ready: bool) -> Result<()> {
+    let base_url = if let Some(url) = url {
+        url
+    } else {
+        let config = config::load()?;
+        local_url(&config.host, config.port)?
+    };
+    let probe_url = base_url.join(if ready { "readyz" } else { "healthz" })?;
+    let response = reqwest::Client::builder()
+        .timeout(Duration::from_secs(5))
+        .build()?
+        .get(probe_url.as_str())
+        .send()
+        .await?;
+    let status = response.status();
+    let body = response.text().await?;
+    ensure!(status.is_success(), "{probe_url} returned {status}: {body}");
+    println!("{body}");
+    Ok(())
+}
+
+/// Url under which the server listening on `host` and `port` can be reached locally.
+pub fn local_url(host: &str, port: u16) -> Result<Url> {
+    let ip = match host.parse()? {
+        IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ip @ (IpAddr::V4(_) | IpAddr::V6(_)) => ip,
+    };
+    Ok(Url::parse(&format!(
+        "http://{}/",
+        SocketAddr::new(ip, port)
+    ))?)
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_local_url() {
+        assert_eq!(
+            local_url("0.0.0.0", 80).unwrap().as_str(),
+            "http://127.0.0.1/"
+        );
+        assert_eq!(
+            local_url("::", 8000).unwrap().as_str(),
+            "http://[::1]:8000/"
+        );
+        assert_eq!(
+            local_url("10.0.0.1", 8000).unwrap().as_str(),
+            "http://10.0.0.1:8000/"
+        );
+        assert!(local_url("localhost", 80).is_err());
+    }
+}
@@ -0,0 +1,85 @@
+use clap::{Parser, Subcommand};
+use url::Url;
+
+mod check_config;
+mod gen_nginx;
+mod healthcheck;
+mod sessions;
+
+pub use check_config::check_config;
+pub use gen_nginx::gen_nginx;
+pub use healthcheck::healthcheck;
+pub use sessions::sessions;
+
+#[derive(Parser)]
+#[command(version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Check whether a running instance is healthy (e.g. in a docker healthcheck)
+    Healthcheck {
+        /// Check readiness (i.e. whether redis and keycloak are reachable) instead of liveness
+        #[arg(long)]
+        ready: bool,
+        /// Base url of the instance to check [default: derived from HOST and PORT]
+        #[arg(long)]
+        url: Option<Url>,
+    },
+    /// Validate the config and check the connection to keycloak and redis
+    CheckConfig,
+    /// Print nginx config snippets for protecting locations
+    GenNginx {
+        /// Role that is required to access the protected locations [default: use the policy file]
+        #[arg(long)]
+        role: Option<String>,
+        /// Base url under which nginx can reach nginx-keycloak [default: derived from HOST and PORT]
+        #[arg(long)]
+        url: Option<Url>,
+        /// Name of the internal location for auth requests
+        #[arg(long, default_value = ".auth")]
+        location: String,
+    },
+    /// Manage the sessions stored in redis
+    Sessions {
+        #[command(subcommand)]
+        command: SessionsCommand,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum SessionsCommand {
+    /// List all sessions
+    List {
+        /// Only list the sessions of the user with this sub
+        #[arg(long)]
+        user: Option<String>,
+    },
+    /// Show the details of a session
+    Show {
+        /// Session id or a unique prefix of it (as shown by `sessions list`)
+        id: String,
+    },
+    /// Revoke a session or all sessions of a user
+    Revoke {
+        /// Session id or a unique prefix of it (as shown by `sessions list`)
+        #[arg(required_unless_present = "user")]
+        id: Option<String>,
+        /// Revoke all sessions of the user with this sub
+        #[arg(long, conflicts_with = "id")]
+        user: Option<String>,
+    },
+    /// Revoke all sessions
+    Purge {
+        /// Confirm that all sessions should be revoked
+        #[arg(long)]
+        yes: bool,
+    },
+    /// Write all sessions as JSON to stdout
+    Export,
+    /// Read sessions written by `sessions export` from stdin
+    Import,
+}
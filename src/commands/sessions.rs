@@ -0,0 +1,144 @@
+use std::{io, time::Duration};
+
+use eyre::{bail, ensure, Result};
+
+use super::SessionsCommand;
+use crate::{
+    config,
+    redis::{unix_time, Redis, SessionDetails},
+};
+
+pub async fn sessions(command: SessionsCommand) -> Result<()> {
+    let config = config::load()?;
+    // tokens are never decrypted here, so the session encryption keys are not needed
+    let redis = Redis::new(
+        config.redis_url.expose(),
+        None,
+        config.session_allowed_ttl,
+        config.session_forbidden_ttl,
+        config.session_grace_period,
+        None,
+        Duration::ZERO,
+    )?;
+    match command {
+        SessionsCommand::List { user } => list(&redis, user.as_deref()).await,
+        SessionsCommand::Show { id } => show(&redis, &id).await,
+        SessionsCommand::Revoke { id: Some(id), .. } => {
+            let key = find_session(&redis, &id).await?;
+            ensure!(redis.delete_session(&key).await?, "session has expired");
+            println!("revoked session {}", session_id(&key));
+            Ok(())
+        }
+        SessionsCommand::Revoke {
+            user: Some(user), ..
+        } => {
+            let revoked = redis.delete_user_sessions(&user).await?;
+            println!("revoked {revoked} session(s) of user {user}");
+            Ok(())
+        }
+        SessionsCommand::Revoke { .. } => bail!("either a session id or --user must be given"),
+        SessionsCommand::Purge { yes } => {
+            ensure!(yes, "this revokes all sessions, pass --yes to continue");
+            let revoked = redis.delete_all_sessions().await?;
+            println!("revoked {revoked} session(s)");
+            Ok(())
+        }
+        SessionsCommand::Export => {
+            let sessions = redis.export_sessions().await?;
+            serde_json::to_writer_pretty(io::stdout().lock(), &sessions)?;
+            println!();
+            eprintln!("exported {} session(s)", sessions.len());
+            Ok(())
+        }
+        SessionsCommand::Import => {
+            let sessions: Vec<_> = serde_json::from_reader(io::stdin().lock())?;
+            redis.import_sessions(&sessions).await?;
+            eprintln!("imported {} session(s)", sessions.len());
+            Ok(())
+        }
+    }
+}
+
+async fn list(redis: &Redis, user: Option<&str>) -> Result<()> {
+    let mut sessions = redis.list_sessions(user).await?;
+    sessions.sort_by_key(|session| session.created_at);
+    let now = unix_time();
+    println!(
+        "{:<16}  {:<20}  {:<36}  {:<9}  {:<9}  IP",
+        "ID", "USERNAME", "SUB", "CREATED", "LAST USED"
+    );
+    for session in sessions {
+        println!(
+            "{:<16}  {:<20}  {:<36}  {:<9}  {:<9}  {}",
+            session.id.get(..16).unwrap_or(&session.id),
+            session.username.as_deref().unwrap_or("-"),
+            session.sub.as_deref().unwrap_or("-"),
+            age(session.created_at, now),
+            session
+                .last_used_at
+                .map_or_else(|| "-".to_owned(), |last_used_at| age(last_used_at, now)),
+            session.ip.as_deref().unwrap_or("-"),
+        );
+    }
+    Ok(())
+}
+
+async fn show(redis: &Redis, id_prefix: &str) -> Result<()> {
+    let key = find_session(redis, id_prefix).await?;
+    let Some(SessionDetails { fields, ttl }) = redis.session_details(&key).await? else {
+        bail!("session has expired");
+    };
+    println!("id: {}", session_id(&key));
+    match ttl {
+        Some(ttl) => println!("expires in: {ttl}s"),
+        None => println!("expires in: never"),
+    }
+    for (field, value) in fields {
+        println!("{field}: {value}");
+    }
+    Ok(())
+}
+
+/// Find the key of the only session whose id starts with `id_prefix`.
+async fn find_session(redis: &Redis, id_prefix: &str) -> Result<String> {
+    ensure!(
+        !id_prefix.is_empty() && id_prefix.chars().all(|c| c.is_ascii_hexdigit()),
+        "invalid session id: {id_prefix}"
+    );
+    let mut keys = redis.find_sessions(&id_prefix.to_ascii_lowercase()).await?;
+    match keys.len() {
+        0 => bail!("no session found with id {id_prefix}"),
+        1 => Ok(keys.remove(0)),
+        n => bail!("{n} sessions found with id {id_prefix}, use a longer prefix"),
+    }
+}
+
+fn session_id(key: &str) -> &str {
+    key.trim_start_matches("session:")
+}
+
+/// Time since `timestamp` in a human readable form, e.g. `5m ago`.
+fn age(timestamp: u64, now: u64) -> String {
+    let secs = now.saturating_sub(timestamp);
+    match secs {
+        0..=59 => format!("{secs}s ago"),
+        60..=3599 => format!("{}m ago", secs / 60),
+        3600..=86399 => format!("{}h ago", secs / 3600),
+        _ => format!("{}d ago", secs / 86400),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_age() {
+        assert_eq!(age(1000, 1000), "0s ago");
+        assert_eq!(age(1000, 1059), "59s ago");
+        assert_eq!(age(1000, 1060), "1m ago");
+        assert_eq!(age(1000, 1000 + 2 * 3600 + 59), "2h ago");
+        assert_eq!(age(1000, 1000 + 3 * 86400), "3d ago");
+        assert_eq!(age(1001, 1000), "0s ago");
+    }
+}
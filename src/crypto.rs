@@ -0,0 +1,117 @@
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm, Nonce,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use eyre::{bail, eyre, Result};
+use rand::Rng;
+
+const NONCE_LEN: usize = 12;
+
+/// AEAD cipher used to encrypt session data at rest.
+///
+/// Values are always encrypted with the first key. Decryption tries all keys in order, so
+/// values written before a key rotation can still be read as long as the old key is kept.
+pub struct Cipher {
+    keys: Vec<Aes256Gcm>,
+}
+
+impl Cipher {
+    pub fn new(keys: &[String]) -> Result<Self> {
+        if keys.is_empty() {
+            bail!("no encryption keys configured");
+        }
+        Ok(Self {
+            keys: keys
+                .iter()
+                .map(|key| {
+                    let key = STANDARD.decode(key.trim())?;
+                    Aes256Gcm::new_from_slice(&key)
+                        .map_err(|_| eyre!("encryption keys must be exactly 32 bytes long"))
+                })
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    /// Encrypt `plaintext` and bind the ciphertext to `aad`.
+    pub fn encrypt(&self, plaintext: &str, aad: &str) -> Result<String> {
+        let mut nonce = [0; NONCE_LEN];
+        rand::thread_rng().fill(&mut nonce);
+        let mut data = nonce.to_vec();
+        data.extend(
+            self.keys[0]
+                .encrypt(
+                    Nonce::from_slice(&nonce),
+                    Payload {
+                        msg: plaintext.as_bytes(),
+                        aad: aad.as_bytes(),
+                    },
+                )
+                .map_err(|_| eyre!("could not encrypt value"))?,
+        );
+        Ok(STANDARD.encode(data))
+    }
+
+    /// Decrypt a value produced by [`Cipher::encrypt`] with the same `aad`.
+    ///
+    /// Returns `None` if the value cannot be decrypted with any of the configured keys.
+    pub fn decrypt(&self, ciphertext: &str, aad: &str) -> Option<String> {
+        let data = STANDARD.decode(ciphertext).ok()?;
+        if data.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, msg) = data.split_at(NONCE_LEN);
+        self.keys
+            .iter()
+            .find_map(|key| {
+                key.decrypt(
+                    Nonce::from_slice(nonce),
+                    Payload {
+                        msg,
+                        aad: aad.as_bytes(),
+                    },
+                )
+                .ok()
+            })
+            .and_then(|plaintext| String::from_utf8(plaintext).ok())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    const KEY1: &str = "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=";
+    const KEY2: &str = "ZmVkY2JhOTg3NjU0MzIxMGZlZGNiYTk4NzY1NDMyMTA=";
+
+    #[test]
+    fn test_new_err() {
+        assert!(Cipher::new(&[]).is_err());
+        assert!(Cipher::new(&["not base64!".to_owned()]).is_err());
+        assert!(Cipher::new(&["c2hvcnQ=".to_owned()]).is_err());
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let cipher = Cipher::new(&[KEY1.to_owned()]).unwrap();
+        let encrypted = cipher.encrypt("my token", "aad").unwrap();
+        assert_ne!(encrypted, "my token");
+        assert_eq!(cipher.decrypt(&encrypted, "aad").unwrap(), "my token");
+        assert_eq!(cipher.decrypt(&encrypted, "other aad"), None);
+        assert_eq!(cipher.decrypt("garbage", "aad"), None);
+    }
+
+    #[test]
+    fn test_rotation() {
+        let old = Cipher::new(&[KEY1.to_owned()]).unwrap();
+        let new = Cipher::new(&[KEY2.to_owned(), KEY1.to_owned()]).unwrap();
+        let encrypted_old = old.encrypt("my token", "aad").unwrap();
+        assert_eq!(new.decrypt(&encrypted_old, "aad").unwrap(), "my token");
+
+        let encrypted_new = new.encrypt("my token", "aad").unwrap();
+        assert_eq!(old.decrypt(&encrypted_new, "aad"), None);
+        let rotated = Cipher::new(&[KEY2.to_owned()]).unwrap();
+        assert_eq!(rotated.decrypt(&encrypted_new, "aad").unwrap(), "my token");
+    }
+}
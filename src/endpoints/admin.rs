@@ -0,0 +1,111 @@
+use axum::{
+    extract::{Path, State},
+    headers::{authorization::Bearer, Authorization, HeaderMapExt},
+    http::{HeaderMap, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tracing::{error, info};
+
+use crate::{
+    endpoints::AdminState,
+    error::Error,
+    redis::{is_session_key, SessionInfo},
+};
+
+/// Reject requests without the configured admin token (if any).
+pub async fn require_token<B>(
+    State(state): State<AdminState>,
+    headers: HeaderMap,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if let Some(token) = &state.token {
+        let provided = headers.typed_get::<Authorization<Bearer>>();
+        // compare digests, so that the comparison doesn't leak the length of the token
+        let valid = provided.is_some_and(|provided| {
+            Sha256::digest(provided.token()) == Sha256::digest(token.expose())
+        });
+        if !valid {
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+    }
+    next.run(request).await
+}
+
+pub async fn list_sessions(
+    State(state): State<AdminState>,
+) -> Result<Json<Vec<SessionInfo>>, StatusCode> {
+    let oidc = state.oidc.current();
+    let sessions = oidc
+        .redis()
+        .list_sessions(None)
+        .await
+        .map_err(|err| failed(&err))?;
+    Ok(Json(sessions))
+}
+
+pub async fn list_user_sessions(
+    State(state): State<AdminState>,
+    Path(sub): Path<String>,
+) -> Result<Json<Vec<SessionInfo>>, StatusCode> {
+    let oidc = state.oidc.current();
+    let sessions = oidc
+        .redis()
+        .list_sessions(Some(&sub))
+        .await
+        .map_err(|err| failed(&err))?;
+    Ok(Json(sessions))
+}
+
+pub async fn revoke_session(
+    State(state): State<AdminState>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, StatusCode> {
+    let key = format!("session:{id}");
+    if !is_session_key(&key) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let oidc = state.oidc.current();
+    let deleted = oidc
+        .redis()
+        .delete_session(&key)
+        .await
+        .map_err(|err| failed(&err))?;
+    if deleted {
+        info!("revoked session {key}");
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(StatusCode::NOT_FOUND)
+    }
+}
+
+#[derive(Serialize)]
+pub struct Revoked {
+    revoked: u64,
+}
+
+pub async fn revoke_user_sessions(
+    State(state): State<AdminState>,
+    Path(sub): Path<String>,
+) -> Result<Json<Revoked>, StatusCode> {
+    let oidc = state.oidc.current();
+    let revoked = oidc
+        .redis()
+        .delete_user_sessions(&sub)
+        .await
+        .map_err(|err| failed(&err))?;
+    info!("revoked {revoked} session(s) of user {sub}");
+    Ok(Json(Revoked { revoked }))
+}
+
+fn failed(err: &Error) -> StatusCode {
+    error!("admin request failed: {err}");
+    match err {
+        Error::Unavailable(..) => StatusCode::SERVICE_UNAVAILABLE,
+        Error::InvalidSession(_) | Error::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
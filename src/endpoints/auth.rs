@@ -3,34 +3,96 @@ use std::sync::Arc;
 use axum::{
     extract::{Query, State},
     headers::{Cookie, HeaderMapExt},
-    http::{HeaderMap, StatusCode},
-    response::{IntoResponse, Result},
+    http::{HeaderMap, HeaderValue, Method, StatusCode},
+    response::{AppendHeaders, IntoResponse, Response, Result},
 };
 use eyre::Report;
-use log::{debug, error};
+use rand::{distributions::Alphanumeric, Rng};
 use serde::Deserialize;
+use tracing::{debug, error, field, info, info_span, warn, Instrument, Span};
 use url::Url;
 
-use crate::oidc::{CodeAuth, Session, OIDC};
+use crate::{
+    audit::{AuditLog, Event, EventKind},
+    client::ClientInfo,
+    endpoints::AuthState,
+    error::{Dependency, Error},
+    metrics::{self, Outcome},
+    oidc::{CodeAuth, Decision, Session, OIDC},
+    policy::{Access, Mode, Requirement},
+    redis::{session_key, unix_time, Identity},
+    telemetry,
+};
+
+/// Url to redirect to if the request has been denied with `401 Unauthorized`.
+pub const REDIRECT_HEADER: &str = "X-Auth-Redirect";
+/// Cookie that nginx has to set on the response to the client.
+pub const COOKIE_HEADER: &str = "X-Auth-Cookie";
+/// Username of the authenticated user, if the request has been allowed.
+pub const USER_HEADER: &str = "X-Auth-User";
+/// Subject (user id) of the authenticated user, if the request has been allowed.
+pub const SUB_HEADER: &str = "X-Auth-Sub";
+
+const SESSION_COOKIE: &str = "_keycloak_auth_session";
 
 pub async fn auth(
-    State(oidc): State<Arc<OIDC>>,
-    Query(AuthQuery { role }): Query<AuthQuery>,
+    State(state): State<AuthState>,
+    method: Method,
+    Query(query): Query<AuthQuery>,
     headers: HeaderMap,
+) -> Response {
+    // reuse the request id generated by nginx, so that log lines can be correlated
+    let request_id = headers
+        .get("x-request-id")
+        .and_then(|x| x.to_str().ok())
+        .map_or_else(generate_request_id, ToOwned::to_owned);
+    let oidc = state.oidc.current();
+    let method = request_method(&method, &headers, oidc.trusts_original_method());
+    let span = info_span!(
+        "auth",
+        request_id,
+        method,
+        role = field::Empty,
+        session = field::Empty,
+        decision = field::Empty
+    );
+    telemetry::set_parent(&span, &headers);
+    // responses are logged while rendering them, so this has to happen within the span as well
+    async move {
+        handle(
+            &oidc,
+            state.audit.as_deref(),
+            request_id,
+            query,
+            &method,
+            &headers,
+        )
+        .await
+        .into_response()
+    }
+    .instrument(span)
+    .await
+}
+
+async fn handle(
+    oidc: &OIDC,
+    audit: Option<&AuditLog>,
+    request_id: String,
+    query: AuthQuery,
+    method: &str,
+    headers: &HeaderMap,
 ) -> axum::response::Result<AuthResponse> {
-    let request_uri = Url::parse(
-        headers
-            .get("x-request-uri")
-            .ok_or(AuthResponse::InternalError(
-                "x-request-uri header not found",
-                None,
-            ))?
-            .to_str()
-            .map_err(|err| {
-                AuthResponse::InternalError("invalid x-request-uri header", Some(err.into()))
-            })?,
-    )
-    .map_err(|err| {
+    let raw_request_uri = headers
+        .get("x-request-uri")
+        .ok_or(AuthResponse::InternalError(
+            "x-request-uri header not found",
+            None,
+        ))?
+        .to_str()
+        .map_err(|err| {
+            AuthResponse::InternalError("invalid x-request-uri header", Some(err.into()))
+        })?;
+    let request_uri = Url::parse(raw_request_uri).map_err(|err| {
         AuthResponse::InternalError(
             "could not parse url in x-request-uri header",
             Some(err.into()),
@@ -43,67 +105,297 @@ pub async fn auth(
     let login_url = oidc
         .create_login_url(&request_uri, &callback_url)
         .map_err(|err| AuthResponse::InternalError("could not create login url", Some(err)))?;
+    let client = oidc.client_policy().client_info(headers);
 
     if request_uri.path() == oidc.auth_callback_path {
         CallbackRequest {
+            request_id,
+            role: query.role.unwrap_or_default(),
             request_uri,
             callback_url,
             login_url,
+            client,
         }
-        .handle(&oidc)
+        .handle(oidc, audit)
         .await
+    } else if oidc.auth_logout_path.as_deref() == Some(request_uri.path()) {
+        logout(oidc, session_id(headers), &request_uri).await
     } else {
+        let access = access(
+            oidc,
+            query,
+            method,
+            request_uri.host_str().unwrap_or_default(),
+            raw_path(raw_request_uri),
+            headers,
+        )?;
+        if let Some(requirement) = access.as_ref().and_then(Access::requirement) {
+            Span::current().record("role", requirement.key());
+        }
         AuthRequest {
-            session_id: headers.typed_get::<Cookie>().and_then(|cookies| {
-                cookies
-                    .get("_keycloak_auth_session")
-                    .map(std::borrow::ToOwned::to_owned)
-            }),
-            role,
+            session_id: session_id(headers),
+            request_id,
+            access,
+            request_uri,
             login_url,
+            client,
         }
-        .handle(&oidc)
+        .handle(oidc, audit)
         .await
     }
 }
 
+/// End the session of the user, if any, remove the session cookie and redirect to the start page.
+async fn logout(
+    oidc: &OIDC,
+    session_id: Option<String>,
+    request_uri: &Url,
+) -> Result<AuthResponse> {
+    Span::current().record("decision", "logout");
+    if let Some(session_id) = session_id {
+        Span::current().record("session", session_key(&session_id));
+        if oidc.logout(&session_id).await.map_err(AuthResponse::from)? {
+            info!("session has been ended");
+        }
+    }
+    let redirect_url = request_uri.join("/").map_err(|err| {
+        AuthResponse::InternalError("could not create logout redirect url", Some(err.into()))
+    })?;
+    Ok(AuthResponse::Logout(redirect_url))
+}
+
+fn session_id(headers: &HeaderMap) -> Option<String> {
+    headers
+        .typed_get::<Cookie>()
+        .and_then(|cookies| cookies.get(SESSION_COOKIE).map(ToOwned::to_owned))
+}
+
+/// Path of a url as it has been requested. Unlike [`Url::path`], `.` and `..` segments and
+/// backslashes are kept, since nginx passes them on to the upstream service unchanged.
+fn raw_path(url: &str) -> &str {
+    let without_scheme = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let path = without_scheme
+        .find(['/', '?', '#'])
+        .map_or("", |start| &without_scheme[start..]);
+    match path.split(['?', '#']).next() {
+        Some(path) if !path.is_empty() => path,
+        _ => "/",
+    }
+}
+
+/// Determine how the request is authorized, either from the `mode` and `role` parameters or
+/// from the policy. Returns `None` if no rule of the policy matches the request. `path` is the
+/// raw path of the request (see [`raw_path`]).
+fn access(
+    oidc: &OIDC,
+    AuthQuery {
+        role,
+        write_role,
+        mode,
+    }: AuthQuery,
+    method: &str,
+    host: &str,
+    path: &str,
+    headers: &HeaderMap,
+) -> Result<Option<Access>, AuthResponse> {
+    if oidc.allows_cors_preflight() && is_cors_preflight(method, headers) {
+        return Ok(Some(Access::Public));
+    }
+    match (mode, role) {
+        (Some(Mode::Public), _) => return Ok(Some(Access::Public)),
+        (Some(Mode::Optional), _) => return Ok(Some(Access::Optional)),
+        (Some(Mode::Required) | None, Some(role)) => {
+            let role = match write_role {
+                Some(write_role) if !is_safe_method(method) => write_role,
+                _ => role,
+            };
+            return Ok(Some(Access::Required(Requirement::role(role))));
+        }
+        (Some(Mode::Required) | None, None) => {}
+    }
+    let policy = oidc.policy().ok_or(AuthResponse::InternalError(
+        "role parameter missing and no policy configured",
+        None,
+    ))?;
+    Ok(policy.access(host, path, method).cloned())
+}
+
+/// nginx issues the auth request with the method of the original request. If it has been changed
+/// (e.g. with `proxy_method`), nginx has to pass the original method in `X-Original-Method`,
+/// which is only used if enabled, as it may have been set by the client otherwise.
+fn request_method(method: &Method, headers: &HeaderMap, trust_original_method: bool) -> String {
+    headers
+        .get("x-original-method")
+        .filter(|_| trust_original_method)
+        .and_then(|x| x.to_str().ok())
+        .map_or_else(|| method.to_string(), str::to_ascii_uppercase)
+}
+
+/// Methods that don't modify anything, which only require `role` even if `write_role` is set.
+fn is_safe_method(method: &str) -> bool {
+    matches!(method, "GET" | "HEAD" | "OPTIONS" | "TRACE")
+}
+
+/// CORS preflight requests are sent by browsers without cookies, so they can never be
+/// authenticated.
+fn is_cors_preflight(method: &str, headers: &HeaderMap) -> bool {
+    method == "OPTIONS"
+        && headers.contains_key("origin")
+        && headers.contains_key("access-control-request-method")
+}
+
+fn session_cookie(session_id: &str) -> String {
+    format!("{SESSION_COOKIE}={session_id}; Secure; HttpOnly; Path=/")
+}
+
+/// Cookie that removes the session cookie from the browser.
+fn expired_session_cookie() -> String {
+    format!("{SESSION_COOKIE}=; Secure; HttpOnly; Path=/; Max-Age=0")
+}
+
+fn generate_request_id() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(16)
+        .map(char::from)
+        .collect()
+}
+
+fn audit_event(
+    kind: EventKind,
+    request_id: &str,
+    role: &str,
+    url: &Url,
+    decision: &'static str,
+    identity: Option<&Identity>,
+    cache_hit: bool,
+) -> Event {
+    Event {
+        timestamp: unix_time(),
+        kind,
+        request_id: request_id.to_owned(),
+        sub: identity.and_then(|identity| identity.sub.clone()),
+        username: identity.and_then(|identity| identity.username.clone()),
+        role: role.to_owned(),
+        host: url.host_str().map(ToOwned::to_owned),
+        path: url.path().to_owned(),
+        decision,
+        cache_hit,
+        client_ip: None,
+        user_agent: None,
+    }
+}
+
 #[derive(Deserialize)]
 pub struct AuthQuery {
-    role: String,
+    role: Option<String>,
+    /// Role that is required instead of `role` for methods that may modify something.
+    write_role: Option<String>,
+    mode: Option<Mode>,
 }
 
 struct AuthRequest {
+    request_id: String,
     session_id: Option<String>,
-    role: String,
+    /// `None` if no rule of the policy matches the request.
+    access: Option<Access>,
+    request_uri: Url,
     login_url: Url,
+    client: ClientInfo,
 }
 
 impl AuthRequest {
-    async fn handle(self, oidc: &OIDC) -> Result<AuthResponse> {
-        if let Some(session_id) = self.session_id {
+    async fn handle(self, oidc: &OIDC, audit: Option<&AuditLog>) -> Result<AuthResponse> {
+        let authenticated = Requirement::authenticated();
+        let requirement = match &self.access {
+            Some(Access::Required(requirement)) => requirement,
+            Some(Access::Optional) => &authenticated,
+            Some(Access::Public) => {
+                self.record(audit, Outcome::Public, None);
+                return Ok(AuthResponse::anonymous());
+            }
+            None => {
+                debug!("no policy rule matches the request");
+                self.record(audit, Outcome::Forbidden, None);
+                return Ok(AuthResponse::Forbidden);
+            }
+        };
+        let optional = matches!(self.access, Some(Access::Optional));
+        if let Some(session_id) = &self.session_id {
+            Span::current().record("session", session_key(session_id));
             match oidc
-                .is_authorized(session_id.as_str(), self.role.as_str())
+                .is_authorized(session_id.as_str(), requirement, &self.client)
                 .await
             {
-                Ok(true) => return Ok(AuthResponse::Ok),
-                Ok(false) => return Ok(AuthResponse::Forbidden),
-                Err(err) => {
+                Ok(decision) if decision.allowed => {
+                    self.record(audit, Outcome::Ok, Some(&decision));
+                    return Ok(AuthResponse::Ok {
+                        identity: Some(decision.identity),
+                        new_session_id: decision.new_session_id,
+                    });
+                }
+                Ok(decision) => {
+                    self.record(audit, Outcome::Forbidden, Some(&decision));
+                    return Ok(AuthResponse::Forbidden);
+                }
+                Err(Error::InvalidSession(err)) => {
                     debug!("is_authorized failed: {:?}", err);
                 }
+                // anonymous users are allowed anyway, so there is no need to fail the request
+                Err(err) if optional => {
+                    warn!("could not identify user, continuing anonymously: {err}");
+                }
+                Err(err) => {
+                    self.record(audit, Outcome::Error, None);
+                    return Err(AuthResponse::from(err).into());
+                }
             };
         }
+        if optional {
+            self.record(audit, Outcome::Anonymous, None);
+            return Ok(AuthResponse::anonymous());
+        }
+        self.record(audit, Outcome::Redirect, None);
         Ok(AuthResponse::RedirectToLogin(self.login_url))
     }
+
+    /// Count an authorization decision, attach it to the request span and write it to the
+    /// audit log.
+    fn record(&self, audit: Option<&AuditLog>, outcome: Outcome, decision: Option<&Decision>) {
+        metrics::auth_decision(outcome);
+        Span::current().record("decision", outcome.as_str());
+        debug!("request handled");
+        if let Some(audit) = audit {
+            audit.record(
+                audit_event(
+                    EventKind::Authorization,
+                    &self.request_id,
+                    self.access
+                        .as_ref()
+                        .and_then(Access::requirement)
+                        .map_or("", Requirement::key),
+                    &self.request_uri,
+                    outcome.as_str(),
+                    decision.map(|decision| &*decision.identity),
+                    decision.is_some_and(|decision| decision.cache_hit),
+                )
+                .with_client(&self.client),
+            );
+        }
+    }
 }
 
 struct CallbackRequest {
+    request_id: String,
+    role: String,
     request_uri: Url,
     callback_url: Url,
     login_url: Url,
+    client: ClientInfo,
 }
 
 impl CallbackRequest {
-    async fn handle(self, oidc: &OIDC) -> Result<AuthResponse> {
+    async fn handle(self, oidc: &OIDC, audit: Option<&AuditLog>) -> Result<AuthResponse> {
         let get_param = |key| {
             self.request_uri
                 .query_pairs()
@@ -120,51 +412,129 @@ impl CallbackRequest {
             AuthResponse::RedirectToLogin(self.login_url.clone())
         })?;
 
-        let Session { session_id, .. } = oidc
-            .create_session(CodeAuth {
-                code,
-                callback_url: self.callback_url,
-            })
-            .await
-            .map_err(|err| {
+        let result = oidc
+            .create_session(
+                CodeAuth {
+                    code,
+                    callback_url: self.callback_url,
+                },
+                &self.client,
+            )
+            .await;
+        metrics::callback(result.is_ok());
+        let Session {
+            session_id,
+            userinfo,
+        } = result.map_err(|err| match err {
+            Error::InvalidSession(err) => {
                 debug!("could not create session: {:?}", err);
                 AuthResponse::RedirectToLogin(self.login_url.clone())
-            })?;
+            }
+            err @ (Error::Unavailable(..) | Error::Internal(_)) => err.into(),
+        })?;
+
+        if let Some(audit) = audit {
+            audit.record(
+                audit_event(
+                    EventKind::Login,
+                    &self.request_id,
+                    &self.role,
+                    &state,
+                    "login",
+                    Some(&Identity::from(&userinfo)),
+                    false,
+                )
+                .with_client(&self.client),
+            );
+        }
 
         Ok(AuthResponse::StoreSession(session_id, state))
     }
 }
 
 pub enum AuthResponse {
-    Ok,
+    /// Access granted, with the identity of the user if a valid session exists and a new session
+    /// id if the session id has been rotated.
+    Ok {
+        identity: Option<Arc<Identity>>,
+        new_session_id: Option<String>,
+    },
     Forbidden,
     RedirectToLogin(Url),
     StoreSession(String, Url),
+    /// Session has been ended, remove the cookie and redirect to the given url.
+    Logout(Url),
+    Unavailable(Dependency, Report),
     InternalError(&'static str, Option<Report>),
 }
 
+impl AuthResponse {
+    const fn anonymous() -> Self {
+        Self::Ok {
+            identity: None,
+            new_session_id: None,
+        }
+    }
+}
+
+impl From<Error> for AuthResponse {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::InvalidSession(report) => Self::InternalError("invalid session", Some(report)),
+            Error::Unavailable(dependency, report) => Self::Unavailable(dependency, report),
+            Error::Internal(report) => Self::InternalError("internal error", Some(report)),
+        }
+    }
+}
+
 impl IntoResponse for AuthResponse {
-    fn into_response(self) -> axum::response::Response {
+    fn into_response(self) -> Response {
         match self {
-            Self::Ok => StatusCode::OK.into_response(),
+            Self::Ok {
+                identity,
+                new_session_id,
+            } => {
+                let identity = identity.as_deref();
+                let headers = [
+                    (USER_HEADER, identity.and_then(|x| x.username.clone())),
+                    (SUB_HEADER, identity.and_then(|x| x.sub.clone())),
+                    (COOKIE_HEADER, new_session_id.as_deref().map(session_cookie)),
+                ]
+                .into_iter()
+                .filter_map(|(name, value)| {
+                    // values that can't be sent in a header (e.g. with line breaks) are omitted
+                    Some((name, HeaderValue::from_bytes(value?.as_bytes()).ok()?))
+                });
+                (StatusCode::OK, AppendHeaders(headers)).into_response()
+            }
             Self::Forbidden => StatusCode::FORBIDDEN.into_response(),
-            Self::RedirectToLogin(url) => (
+            Self::RedirectToLogin(url) => {
+                (StatusCode::UNAUTHORIZED, [(REDIRECT_HEADER, url.as_str())]).into_response()
+            }
+            Self::StoreSession(session_id, redirect_url) => (
                 StatusCode::UNAUTHORIZED,
-                [("X-Auth-Redirect", url.as_str())],
+                [
+                    (REDIRECT_HEADER, redirect_url.as_str()),
+                    (COOKIE_HEADER, session_cookie(&session_id).as_str()),
+                ],
             )
                 .into_response(),
-            Self::StoreSession(session_id, redirect_url) => (
+            Self::Logout(redirect_url) => (
                 StatusCode::UNAUTHORIZED,
                 [
-                    ("X-Auth-Redirect", redirect_url.as_str()),
-                    (
-                        "X-Auth-Cookie",
-                        format!("_keycloak_auth_session={session_id}; Secure; HttpOnly; Path=/")
-                            .as_str(),
-                    ),
+                    (REDIRECT_HEADER, redirect_url.as_str()),
+                    (COOKIE_HEADER, expired_session_cookie().as_str()),
                 ],
             )
                 .into_response(),
+            Self::Unavailable(dependency, report) => {
+                warn!("{dependency} unavailable: {:?}", report);
+                let (status, error) = match dependency {
+                    Dependency::Redis => (StatusCode::SERVICE_UNAVAILABLE, "redis_unavailable"),
+                    Dependency::IdP => (StatusCode::BAD_GATEWAY, "idp_unavailable"),
+                };
+                (status, [("X-Auth-Error", error)], error).into_response()
+            }
             Self::InternalError(error, report) => {
                 error!("{}: {:?}", error, report);
                 (StatusCode::INTERNAL_SERVER_ERROR, error).into_response()
@@ -172,3 +542,210 @@ impl IntoResponse for AuthResponse {
         }
     }
 }
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use std::time::Duration;
+
+    use eyre::eyre;
+    use reqwest::Client;
+
+    use super::*;
+    use crate::{policy::Policy, redis::Redis};
+
+    fn oidc() -> OIDC {
+        let redis = Redis::new(
+            "redis://localhost",
+            None,
+            60,
+            10,
+            None,
+            None,
+            Duration::ZERO,
+        );
+        OIDC::new(
+            Client::new(),
+            "http://id.domain.de/realms/my_realm/",
+            "my_oidc_client".to_owned(),
+            "secret".to_owned(),
+            "/_auth/callback".to_owned(),
+            redis.unwrap(),
+            30,
+        )
+        .unwrap()
+    }
+
+    fn required(role: &str) -> Access {
+        Access::Required(Requirement::role(role.to_owned()))
+    }
+
+    fn preflight_headers() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("origin", HeaderValue::from_static("https://app.domain.de"));
+        headers.insert(
+            "access-control-request-method",
+            HeaderValue::from_static("PUT"),
+        );
+        headers
+    }
+
+    #[test]
+    fn test_access_write_role() {
+        let oidc = oidc();
+        let access = |write_role: Option<&str>, method| {
+            let query = AuthQuery {
+                role: Some("viewer".to_owned()),
+                write_role: write_role.map(ToOwned::to_owned),
+                mode: None,
+            };
+            access(
+                &oidc,
+                query,
+                method,
+                "service.domain.de",
+                "/",
+                &HeaderMap::new(),
+            )
+            .ok()
+            .unwrap()
+        };
+        assert_eq!(access(Some("editor"), "GET"), Some(required("viewer")));
+        assert_eq!(access(Some("editor"), "HEAD"), Some(required("viewer")));
+        assert_eq!(access(Some("editor"), "POST"), Some(required("editor")));
+        assert_eq!(access(Some("editor"), "DELETE"), Some(required("editor")));
+        assert_eq!(access(None, "POST"), Some(required("viewer")));
+    }
+
+    #[test]
+    fn test_access_cors_preflight() {
+        let access = |oidc: &OIDC, method, headers: &HeaderMap| {
+            let query = AuthQuery {
+                role: Some("viewer".to_owned()),
+                write_role: None,
+                mode: None,
+            };
+            access(oidc, query, method, "service.domain.de", "/", headers)
+                .ok()
+                .unwrap()
+        };
+        let headers = preflight_headers();
+        let allowed = oidc().with_cors_preflight(true);
+        assert_eq!(access(&allowed, "OPTIONS", &headers), Some(Access::Public));
+        assert_eq!(access(&allowed, "GET", &headers), Some(required("viewer")));
+        assert_eq!(
+            access(&allowed, "OPTIONS", &HeaderMap::new()),
+            Some(required("viewer"))
+        );
+        assert_eq!(
+            access(&oidc(), "OPTIONS", &headers),
+            Some(required("viewer"))
+        );
+    }
+
+    #[test]
+    fn test_raw_path() {
+        assert_eq!(raw_path("https://s.domain.de/a/../b?x=/c#d"), "/a/../b");
+        assert_eq!(raw_path("https://s.domain.de/a\\..\\b"), "/a\\..\\b");
+        assert_eq!(raw_path("https://s.domain.de/%2e%2e/b"), "/%2e%2e/b");
+        assert_eq!(raw_path("https://s.domain.de?x=/c"), "/");
+        assert_eq!(raw_path("https://s.domain.de"), "/");
+    }
+
+    #[tokio::test]
+    async fn test_policy_sees_raw_path() {
+        let path =
+            std::env::temp_dir().join(format!("nginx-keycloak-policy-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"
+[[rules]]
+path = "/.well-known/"
+mode = "public"
+
+[[rules]]
+path = "/admin"
+roles = ["admin"]
+"#,
+        )
+        .unwrap();
+        let oidc = oidc().with_policy(Some(Policy::load(&path).unwrap()));
+        std::fs::remove_file(&path).unwrap();
+
+        let status = |uri: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("x-request-uri", HeaderValue::from_static(uri));
+            let query = AuthQuery {
+                role: None,
+                write_role: None,
+                mode: None,
+            };
+            let oidc = &oidc;
+            async move {
+                handle(oidc, None, "id".to_owned(), query, "GET", &headers)
+                    .await
+                    .into_response()
+                    .status()
+            }
+        };
+        assert_eq!(
+            status("https://s.domain.de/.well-known/x").await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status("https://s.domain.de/admin/x").await,
+            StatusCode::UNAUTHORIZED
+        );
+        // `Url::parse` would resolve these to a public path, but nginx passes them on unchanged
+        for uri in [
+            "https://s.domain.de/admin/../.well-known/x",
+            "https://s.domain.de/admin/%2e%2e/.well-known/x",
+            "https://s.domain.de/admin\\..\\.well-known/x",
+            "https://s.domain.de/admin%2F..%2F.well-known/x",
+        ] {
+            assert_eq!(status(uri).await, StatusCode::FORBIDDEN, "{uri}");
+        }
+    }
+
+    #[test]
+    fn test_request_method() {
+        let mut headers = preflight_headers();
+        assert_eq!(request_method(&Method::POST, &headers, true), "POST");
+        headers.insert("x-original-method", HeaderValue::from_static("options"));
+        // a client must not be able to turn its request into a preflight request
+        assert_eq!(request_method(&Method::GET, &headers, false), "GET");
+        assert_eq!(request_method(&Method::GET, &headers, true), "OPTIONS");
+    }
+
+    #[test]
+    fn test_unavailable_response() {
+        let redis = AuthResponse::from(Error::Unavailable(Dependency::Redis, eyre!("down")))
+            .into_response();
+        assert_eq!(redis.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(redis.headers()["x-auth-error"], "redis_unavailable");
+
+        let idp =
+            AuthResponse::from(Error::Unavailable(Dependency::IdP, eyre!("down"))).into_response();
+        assert_eq!(idp.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(idp.headers()["x-auth-error"], "idp_unavailable");
+
+        let internal = AuthResponse::from(Error::Internal(eyre!("bug"))).into_response();
+        assert_eq!(internal.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(!internal.headers().contains_key("x-auth-error"));
+    }
+
+    #[test]
+    fn test_logout_response() {
+        let response =
+            AuthResponse::Logout(Url::parse("https://service.domain.de/").unwrap()).into_response();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            response.headers()["x-auth-redirect"],
+            "https://service.domain.de/"
+        );
+        assert_eq!(
+            response.headers()["x-auth-cookie"],
+            "_keycloak_auth_session=; Secure; HttpOnly; Path=/; Max-Age=0"
+        );
+    }
+}
@@ -1,13 +1,67 @@
 use std::sync::Arc;
 
-use axum::{routing::get, Router};
+use axum::{
+    middleware,
+    routing::{any, delete, get},
+    Router,
+};
+use metrics_exporter_prometheus::PrometheusHandle;
 
-use crate::oidc::OIDC;
+use crate::{audit::AuditLog, config::Secret, health::Health, reload::SharedOidc};
 
+mod admin;
 mod auth;
+mod health;
+mod metrics;
 
-pub fn router(oidc: OIDC) -> Router {
+pub use auth::{COOKIE_HEADER, REDIRECT_HEADER, SUB_HEADER, USER_HEADER};
+
+#[derive(Clone)]
+pub struct AuthState {
+    pub oidc: SharedOidc,
+    pub audit: Option<Arc<AuditLog>>,
+}
+
+pub fn router(state: AuthState, health: Arc<Health>) -> Router {
     Router::new()
-        .route("/auth", get(auth::auth))
-        .with_state(Arc::new(oidc))
+        .route("/auth", any(auth::auth))
+        .with_state(state)
+        .merge(
+            Router::new()
+                .route("/healthz", get(health::healthz))
+                .route("/readyz", get(health::readyz))
+                .with_state(health),
+        )
+}
+
+#[derive(Clone)]
+pub struct AdminState {
+    pub oidc: SharedOidc,
+    /// Bearer token required for the session management endpoints.
+    pub token: Option<Arc<Secret>>,
+}
+
+/// Endpoints for operators, which may be served on a separate listener. The session management
+/// endpoints are only included if `sessions` is set.
+pub fn admin_router(metrics_handle: PrometheusHandle, sessions: Option<AdminState>) -> Router {
+    let router = Router::new()
+        .route("/metrics", get(metrics::metrics))
+        .with_state(metrics_handle);
+    let Some(state) = sessions else {
+        return router;
+    };
+    router.merge(
+        Router::new()
+            .route("/admin/sessions", get(admin::list_sessions))
+            .route("/admin/sessions/:id", delete(admin::revoke_session))
+            .route(
+                "/admin/users/:sub/sessions",
+                get(admin::list_user_sessions).delete(admin::revoke_user_sessions),
+            )
+            .route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                admin::require_token,
+            ))
+            .with_state(state),
+    )
 }
@@ -0,0 +1,137 @@
+use std::fmt::{self, Display};
+
+use eyre::Report;
+use reqwest::StatusCode;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Errors that can occur while handling a session.
+#[derive(Debug)]
+pub enum Error {
+    /// The session does not exist, has expired or was rejected by the identity provider.
+    InvalidSession(Report),
+    /// A dependency could not be reached or returned an unexpected response.
+    Unavailable(Dependency, Report),
+    /// Any other error, e.g. a bug or a misconfiguration.
+    Internal(Report),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dependency {
+    Redis,
+    IdP,
+}
+
+impl Error {
+    pub fn invalid_session(msg: &'static str) -> Self {
+        Self::InvalidSession(Report::msg(msg))
+    }
+
+    /// Add context to the underlying report without changing the kind of error.
+    #[must_use]
+    pub fn wrap_err(self, msg: &'static str) -> Self {
+        match self {
+            Self::InvalidSession(report) => Self::InvalidSession(report.wrap_err(msg)),
+            Self::Unavailable(dependency, report) => {
+                Self::Unavailable(dependency, report.wrap_err(msg))
+            }
+            Self::Internal(report) => Self::Internal(report.wrap_err(msg)),
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidSession(report) => write!(f, "invalid session: {report}"),
+            Self::Unavailable(dependency, report) => {
+                write!(f, "{dependency} unavailable: {report}")
+            }
+            Self::Internal(report) => write!(f, "internal error: {report}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Display for Dependency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Redis => "redis",
+            Self::IdP => "identity provider",
+        })
+    }
+}
+
+impl From<redis::RedisError> for Error {
+    fn from(err: redis::RedisError) -> Self {
+        Self::Unavailable(Dependency::Redis, err.into())
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    /// Timeouts, connection errors, rate limiting and server errors indicate that the identity
+    /// provider is unavailable. Other client errors (e.g. a rejected client secret) point to a
+    /// misconfiguration, as the responses that invalidate a session are handled by the callers.
+    fn from(err: reqwest::Error) -> Self {
+        match err.status() {
+            Some(status) if status.is_client_error() && status != StatusCode::TOO_MANY_REQUESTS => {
+                Self::Internal(err.into())
+            }
+            _ => Self::Unavailable(Dependency::IdP, err.into()),
+        }
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Self::Internal(err.into())
+    }
+}
+
+impl From<Report> for Error {
+    fn from(report: Report) -> Self {
+        Self::Internal(report)
+    }
+}
+
+/// Add context to errors, like [`eyre::WrapErr`] does for reports.
+pub trait WrapErr<T> {
+    fn wrap_err(self, msg: &'static str) -> Result<T>;
+}
+
+impl<T> WrapErr<T> for Result<T> {
+    fn wrap_err(self, msg: &'static str) -> Self {
+        self.map_err(|err| err.wrap_err(msg))
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    fn status_error(status: u16) -> reqwest::Error {
+        let response = axum::http::Response::builder()
+            .status(status)
+            .body("")
+            .unwrap();
+        reqwest::Response::from(response)
+            .error_for_status()
+            .unwrap_err()
+    }
+
+    #[test]
+    fn test_from_reqwest_error() {
+        assert!(matches!(Error::from(status_error(401)), Error::Internal(_)));
+        assert!(matches!(Error::from(status_error(400)), Error::Internal(_)));
+        assert!(matches!(
+            Error::from(status_error(429)),
+            Error::Unavailable(Dependency::IdP, _)
+        ));
+        assert!(matches!(
+            Error::from(status_error(503)),
+            Error::Unavailable(Dependency::IdP, _)
+        ));
+    }
+}
@@ -0,0 +1,73 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use tokio::sync::Mutex;
+
+use crate::{error, reload::SharedOidc};
+
+/// Readiness checks of the dependencies. Results are cached, so that frequent probes don't put
+/// additional load on redis and the identity provider.
+pub struct Health {
+    oidc: SharedOidc,
+    cache_ttl: Duration,
+    cache: Mutex<Option<(Instant, Arc<Readiness>)>>,
+}
+
+#[derive(Serialize)]
+pub struct Readiness {
+    pub redis: Check,
+    pub idp: Check,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Check {
+    Ok,
+    Error(String),
+}
+
+impl Health {
+    pub fn new(oidc: SharedOidc, cache_ttl: Duration) -> Self {
+        Self {
+            oidc,
+            cache_ttl,
+            cache: Mutex::new(None),
+        }
+    }
+
+    pub async fn readiness(&self) -> Arc<Readiness> {
+        // concurrent probes wait for the running check instead of starting their own
+        let mut cache = self.cache.lock().await;
+        if let Some((checked_at, readiness)) = &*cache {
+            if checked_at.elapsed() < self.cache_ttl {
+                return Arc::clone(readiness);
+            }
+        }
+        let oidc = self.oidc.current();
+        let (redis, idp) = tokio::join!(oidc.check_redis(), oidc.check_idp());
+        let readiness = Arc::new(Readiness {
+            redis: redis.into(),
+            idp: idp.into(),
+        });
+        *cache = Some((Instant::now(), Arc::clone(&readiness)));
+        readiness
+    }
+}
+
+impl Readiness {
+    pub const fn is_ready(&self) -> bool {
+        matches!((&self.redis, &self.idp), (Check::Ok, Check::Ok))
+    }
+}
+
+impl From<error::Result<()>> for Check {
+    fn from(result: error::Result<()>) -> Self {
+        match result {
+            Ok(()) => Self::Ok,
+            Err(err) => Self::Error(err.to_string()),
+        }
+    }
+}
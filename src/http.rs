@@ -0,0 +1,36 @@
+use std::time::Duration;
+
+use eyre::{Context, Result};
+use reqwest::{Certificate, Client, Identity, Proxy};
+
+use crate::config::Config;
+
+/// Build the HTTP client that is shared by all requests to the identity provider.
+pub fn client(config: &Config) -> Result<Client> {
+    let mut builder = Client::builder()
+        .connect_timeout(Duration::from_secs(config.http_connect_timeout))
+        .timeout(Duration::from_secs(config.http_timeout));
+
+    if let Some(path) = &config.http_ca_file {
+        let pem = std::fs::read(path).wrap_err("could not read ca file")?;
+        for cert in Certificate::from_pem_bundle(&pem).wrap_err("could not parse ca file")? {
+            builder = builder.add_root_certificate(cert);
+        }
+    }
+
+    if let Some(proxy) = &config.http_proxy {
+        builder = builder.proxy(Proxy::all(proxy.expose()).wrap_err("invalid proxy url")?);
+    }
+
+    if let Some(cert_path) = &config.http_client_cert_file {
+        let mut pem = std::fs::read(cert_path).wrap_err("could not read client certificate")?;
+        if let Some(key_path) = &config.http_client_key_file {
+            pem.push(b'\n');
+            pem.extend(std::fs::read(key_path).wrap_err("could not read client key")?);
+        }
+        builder = builder
+            .identity(Identity::from_pem(&pem).wrap_err("could not parse client certificate")?);
+    }
+
+    Ok(builder.build()?)
+}
@@ -0,0 +1,67 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, PoisonError, Weak},
+};
+
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+
+/// A set of async locks identified by string keys.
+///
+/// Locks are created on demand and dropped as soon as nobody holds or waits for them anymore.
+#[derive(Default)]
+pub struct KeyedLock {
+    locks: Mutex<HashMap<String, Weak<AsyncMutex<()>>>>,
+}
+
+impl KeyedLock {
+    pub async fn lock(&self, key: &str) -> OwnedMutexGuard<()> {
+        let lock = {
+            let mut locks = self.locks.lock().unwrap_or_else(PoisonError::into_inner);
+            locks.retain(|_, lock| lock.strong_count() > 0);
+            let existing = locks.get(key).and_then(Weak::upgrade);
+            existing.unwrap_or_else(|| {
+                let lock = Arc::new(AsyncMutex::new(()));
+                locks.insert(key.to_owned(), Arc::downgrade(&lock));
+                lock
+            })
+        };
+        lock.lock_owned().await
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_lock() {
+        let locks = Arc::new(KeyedLock::default());
+        let guard = locks.lock("a").await;
+        let _other = locks.lock("b").await;
+
+        let task = tokio::spawn({
+            let locks = Arc::clone(&locks);
+            async move {
+                let _guard = locks.lock("a").await;
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!task.is_finished());
+        drop(guard);
+        tokio::time::timeout(Duration::from_secs(1), task)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cleanup() {
+        let locks = KeyedLock::default();
+        drop(locks.lock("a").await);
+        let _guard = locks.lock("b").await;
+        assert_eq!(locks.locks.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,54 @@
+use std::{env, fmt};
+
+use eyre::{bail, Result};
+use tracing::Level;
+use tracing_subscriber::{filter::Targets, fmt::layer, prelude::*, EnvFilter};
+
+use crate::telemetry;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Pretty,
+    Json,
+}
+
+/// Initialize the global tracing subscriber.
+///
+/// Log levels are configured via `RUST_LOG` and the output format via `LOG_FORMAT` (`pretty` or
+/// `json`). Log records of dependencies using the `log` crate are forwarded as well. Spans are
+/// exported via OpenTelemetry if configured (see [`telemetry::tracer`]).
+pub fn init() -> Result<Format> {
+    let format = match env::var("LOG_FORMAT").as_deref() {
+        Err(_) | Ok("pretty") => Format::Pretty,
+        Ok("json") => Format::Json,
+        Ok(format) => bail!("invalid LOG_FORMAT: {format}"),
+    };
+    let fmt_layer = match format {
+        Format::Pretty => layer().boxed(),
+        Format::Json => layer()
+            .json()
+            .with_current_span(false)
+            .with_span_list(true)
+            .boxed(),
+    };
+    // only export our own spans, independent of the log level
+    let otel_layer = telemetry::tracer()?.map(|tracer| {
+        tracing_opentelemetry::layer()
+            .with_tracer(tracer)
+            .with_filter(Targets::new().with_target(env!("CARGO_CRATE_NAME"), Level::INFO))
+    });
+    tracing_subscriber::registry()
+        .with(fmt_layer.with_filter(EnvFilter::from_default_env()))
+        .with(otel_layer)
+        .try_init()?;
+    Ok(format)
+}
+
+/// Placeholder for secrets in `Debug` implementations.
+pub struct Redacted;
+
+impl fmt::Debug for Redacted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
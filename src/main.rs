@@ -18,59 +18,204 @@
 )]
 #![allow(clippy::module_name_repetitions, clippy::upper_case_acronyms)]
 
-use std::net::SocketAddr;
+use std::{
+    net::{SocketAddr, TcpListener},
+    sync::Arc,
+    time::Duration,
+};
 
+use audit::AuditLog;
 use axum::Server;
-use log::{debug, info};
-use oidc::OIDC;
+use cache::LocalCache;
+use clap::Parser;
+use color_eyre::config::{HookBuilder, Theme};
+use commands::{Cli, Command};
+use endpoints::{AdminState, AuthState};
+use health::Health;
+use listenfd::ListenFd;
+use reload::{Reloader, SharedOidc};
+use sd_notify::NotifyState;
+use shutdown::Shutdown;
+use tracing::{debug, error, info, warn};
 
+mod audit;
+mod breaker;
+mod cache;
+mod client;
+mod commands;
 mod config;
+mod crypto;
 mod endpoints;
+mod error;
+mod health;
+mod http;
+mod lock;
+mod logging;
+mod metrics;
 mod oidc;
+mod policy;
 mod redis;
+mod reload;
+mod shutdown;
+mod telemetry;
 
 #[tokio::main]
 async fn main() -> eyre::Result<()> {
     // initialize logger
-    pretty_env_logger::init();
+    let log_format = logging::init()?;
 
-    // initialize panic and error report handler
-    color_eyre::install()?;
+    // initialize panic and error report handler (without colors in json logs)
+    HookBuilder::default()
+        .theme(match log_format {
+            logging::Format::Pretty => Theme::dark(),
+            logging::Format::Json => Theme::new(),
+        })
+        .install()?;
+
+    let result = match Cli::parse().command {
+        None => serve().await,
+        Some(Command::Healthcheck { ready, url }) => commands::healthcheck(url, ready).await,
+        Some(Command::CheckConfig) => commands::check_config().await,
+        Some(Command::GenNginx {
+            role,
+            url,
+            location,
+        }) => commands::gen_nginx(role.as_deref(), url, &location),
+        Some(Command::Sessions { command }) => commands::sessions(command).await,
+    };
+
+    // export remaining spans
+    telemetry::shutdown();
+
+    result
+}
+
+async fn serve() -> eyre::Result<()> {
+    // initialize metrics recorder
+    let metrics_handle = metrics::install()?;
 
     // load config from environment variables
     info!("loading config");
     let config = config::load()?;
     debug!("config loaded: {config:#?}");
 
-    // load client_secret
-    let client_secret = match config.client_secret {
-        config::ClientSecret::String { client_secret } => client_secret,
-        config::ClientSecret::File { client_secret_file } => {
-            std::fs::read_to_string(client_secret_file)?
+    // start audit log writer
+    let audit = AuditLog::start(&config)?.map(Arc::new);
+
+    // create in-memory decision cache
+    let local_cache = (config.local_cache_size > 0).then(|| {
+        Arc::new(LocalCache::new(
+            config.local_cache_size,
+            Duration::from_secs(config.local_cache_ttl),
+        ))
+    });
+
+    // create oidc client, which is replaced on SIGHUP or when the config files change
+    let reloader = Reloader::new(&config, local_cache)?;
+    let oidc = reloader.oidc();
+    reloader.spawn(&config)?;
+
+    // keep in-memory decision cache in sync with other instances
+    if let Some(listener) = oidc.current().invalidation_listener() {
+        tokio::spawn(listener);
+    }
+
+    // refresh tokens of active sessions in the background
+    if let Some(interval) = config.token_refresh_interval {
+        tokio::spawn(refresh_expiring_sessions(
+            oidc.clone(),
+            Duration::from_secs(interval),
+        ));
+    }
+
+    // cache readiness checks of redis and keycloak
+    let health = Arc::new(Health::new(
+        oidc.clone(),
+        Duration::from_secs(config.readiness_cache_ttl),
+    ));
+
+    // handle SIGTERM and SIGINT
+    let shutdown = Shutdown::on_signal()?;
+
+    // use sockets passed by systemd (socket activation) if available
+    let mut listenfd = ListenFd::from_env();
+
+    // serve admin endpoints on a separate listener if configured
+    let admin_listener = admin_listener(&config, &mut listenfd)?;
+    // sessions can only be managed on a separate listener or with a token
+    let admin_state =
+        (admin_listener.is_some() || config.admin_token.is_some()).then(|| AdminState {
+            oidc: oidc.clone(),
+            token: config.admin_token.clone().map(Arc::new),
+        });
+    let admin_router = endpoints::admin_router(metrics_handle, admin_state);
+    let state = AuthState { oidc, audit };
+    let router = if let Some(admin_listener) = admin_listener {
+        let admin_addr = admin_listener.local_addr()?;
+        info!("starting admin server on {admin_addr}");
+        if !admin_addr.ip().is_loopback() && config.admin_token.is_none() {
+            warn!("sessions can be managed without a token on {admin_addr}, consider setting ADMIN_TOKEN");
         }
+        let admin_server = Server::from_tcp(admin_listener)?
+            .serve(admin_router.into_make_service())
+            .with_graceful_shutdown(shutdown.clone().wait());
+        tokio::spawn(async move {
+            if let Err(err) = admin_server.await {
+                error!("admin server failed: {err}");
+            }
+        });
+        endpoints::router(state, health)
+    } else {
+        endpoints::router(state, health).merge(admin_router)
     };
 
-    // create redis client
-    let redis = redis::Redis::new(
-        &config.redis_url,
-        config.session_allowed_ttl,
-        config.session_forbidden_ttl,
-    )?;
-
-    // create oidc client
-    let oidc = OIDC::new(
-        &config.keycloak_base_url,
-        config.client_id,
-        client_secret,
-        config.auth_callback_path,
-        redis,
-    )?;
-
     // start axum server
-    info!("starting server on {}:{}", config.host, config.port);
-    Server::bind(&SocketAddr::new(config.host.parse()?, config.port))
-        .serve(endpoints::router(oidc).into_make_service())
-        .await?;
+    let listener = match listenfd.take_tcp_listener(0)? {
+        Some(listener) => listener,
+        None => TcpListener::bind(SocketAddr::new(config.host.parse()?, config.port))?,
+    };
+    info!("starting server on {}", listener.local_addr()?);
+    let server = Server::from_tcp(listener)?
+        .serve(router.into_make_service())
+        .with_graceful_shutdown(shutdown.clone().wait());
+    shutdown::notify(NotifyState::Ready);
+
+    // wait for in-flight requests to complete, but not forever
+    let drain_timeout = Duration::from_secs(config.shutdown_timeout);
+    tokio::select! {
+        result = server => result?,
+        () = async {
+            shutdown.wait().await;
+            tokio::time::sleep(drain_timeout).await;
+        } => warn!("shutdown timeout exceeded, dropping remaining connections"),
+    }
+    info!("server stopped");
 
     Ok(())
 }
+
+fn admin_listener(
+    config: &config::Config,
+    listenfd: &mut ListenFd,
+) -> eyre::Result<Option<TcpListener>> {
+    if let Some(listener) = listenfd.take_tcp_listener(1)? {
+        return Ok(Some(listener));
+    }
+    let Some(admin_port) = config.admin_port else {
+        return Ok(None);
+    };
+    Ok(Some(TcpListener::bind(SocketAddr::new(
+        config.admin_host.parse()?,
+        admin_port,
+    ))?))
+}
+
+async fn refresh_expiring_sessions(oidc: SharedOidc, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        if let Err(err) = oidc.current().refresh_expiring_sessions().await {
+            warn!("could not refresh expiring sessions: {err}");
+        }
+    }
+}
@@ -0,0 +1,136 @@
+use std::time::Instant;
+
+use ::metrics::{counter, describe_counter, describe_histogram, histogram, Unit};
+use eyre::Result;
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+
+const AUTH_DECISIONS: &str = "nginx_keycloak_auth_decisions_total";
+const STALE_DECISIONS: &str = "nginx_keycloak_stale_decisions_total";
+const AUDIT_EVENTS_DROPPED: &str = "nginx_keycloak_audit_events_dropped_total";
+const SESSION_CACHE: &str = "nginx_keycloak_session_cache_total";
+const TOKEN_REFRESHES: &str = "nginx_keycloak_token_refreshes_total";
+const CALLBACKS: &str = "nginx_keycloak_callbacks_total";
+const IDP_REQUEST_DURATION: &str = "nginx_keycloak_idp_request_duration_seconds";
+const REDIS_COMMAND_DURATION: &str = "nginx_keycloak_redis_command_duration_seconds";
+
+/// Install the global metrics recorder.
+pub fn install() -> Result<PrometheusHandle> {
+    let handle = PrometheusBuilder::new()
+        .set_buckets(&[
+            0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+        ])?
+        .install_recorder()?;
+
+    describe_counter!(AUTH_DECISIONS, "Authorization decisions by outcome");
+    describe_counter!(
+        STALE_DECISIONS,
+        "Stale decisions served while the identity provider was unavailable"
+    );
+    describe_counter!(
+        AUDIT_EVENTS_DROPPED,
+        "Audit events dropped because the audit log writer could not keep up"
+    );
+    describe_counter!(SESSION_CACHE, "Session cache lookups by result");
+    describe_counter!(TOKEN_REFRESHES, "Token refreshes by result");
+    describe_counter!(CALLBACKS, "Login callbacks by result");
+    describe_histogram!(
+        IDP_REQUEST_DURATION,
+        Unit::Seconds,
+        "Duration of requests to the identity provider"
+    );
+    describe_histogram!(
+        REDIS_COMMAND_DURATION,
+        Unit::Seconds,
+        "Duration of redis operations"
+    );
+
+    Ok(handle)
+}
+
+#[derive(Clone, Copy)]
+pub enum Outcome {
+    Ok,
+    Forbidden,
+    Redirect,
+    Error,
+    /// Allowed without a valid session in optional mode.
+    Anonymous,
+    /// Allowed in public mode.
+    Public,
+}
+
+impl Outcome {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Ok => "ok",
+            Self::Forbidden => "forbidden",
+            Self::Redirect => "redirect",
+            Self::Error => "error",
+            Self::Anonymous => "anonymous",
+            Self::Public => "public",
+        }
+    }
+}
+
+pub fn auth_decision(outcome: Outcome) {
+    counter!(AUTH_DECISIONS, "outcome" => outcome.as_str()).increment(1);
+}
+
+pub fn stale_decision() {
+    counter!(STALE_DECISIONS).increment(1);
+}
+
+pub fn audit_event_dropped() {
+    counter!(AUDIT_EVENTS_DROPPED).increment(1);
+}
+
+pub fn session_cache(hit: bool) {
+    counter!(SESSION_CACHE, "result" => if hit { "hit" } else { "miss" }).increment(1);
+}
+
+pub fn token_refresh(success: bool) {
+    counter!(TOKEN_REFRESHES, "result" => result(success)).increment(1);
+}
+
+pub fn callback(success: bool) {
+    counter!(CALLBACKS, "result" => result(success)).increment(1);
+}
+
+const fn result(success: bool) -> &'static str {
+    if success {
+        "success"
+    } else {
+        "failure"
+    }
+}
+
+/// Start measuring the duration of a request to the identity provider.
+pub fn idp_timer(endpoint: &'static str) -> Timer {
+    Timer {
+        name: IDP_REQUEST_DURATION,
+        label: ("endpoint", endpoint),
+        start: Instant::now(),
+    }
+}
+
+/// Start measuring the duration of a redis operation.
+pub fn redis_timer(operation: &'static str) -> Timer {
+    Timer {
+        name: REDIS_COMMAND_DURATION,
+        label: ("operation", operation),
+        start: Instant::now(),
+    }
+}
+
+/// Records the time elapsed since its creation when dropped.
+pub struct Timer {
+    name: &'static str,
+    label: (&'static str, &'static str),
+    start: Instant,
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        histogram!(self.name, self.label.0 => self.label.1).record(self.start.elapsed());
+    }
+}
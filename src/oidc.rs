@@ -1,42 +1,140 @@
-use eyre::{Context, Result};
-use log::debug;
+use std::{fmt, future::Future, ops::ControlFlow, sync::Arc, time::Duration};
+
+use eyre::{bail, eyre, Result};
 use rand::{distributions::Alphanumeric, Rng};
-use reqwest::Client;
-use serde::Deserialize;
+use reqwest::{Client, StatusCode};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, instrument, warn};
 use url::Url;
 
-use crate::redis::{Redis, SessionCache};
+use crate::{
+    breaker::CircuitBreaker,
+    client::{ClientInfo, ClientPolicy},
+    error::{self, Dependency, Error, WrapErr},
+    lock::KeyedLock,
+    logging::Redacted,
+    metrics,
+    policy::{Policy, Requirement},
+    redis::{session_key, unix_time, Identity, Redis, SessionCache, SessionState},
+    telemetry,
+};
 
 pub struct OIDC {
+    http: Client,
+    issuer: String,
     auth_url: Url,
+    discovery_url: Url,
     token_url: Url,
     userinfo_url: Url,
+    logout_url: Url,
     client_id: String,
     client_secret: String,
     pub auth_callback_path: String,
+    pub auth_logout_path: Option<String>,
     redis: Redis,
+    session_locks: KeyedLock,
+    token_refresh_window: u64,
+    client_policy: ClientPolicy,
+    policy: Option<Policy>,
+    allow_cors_preflight: bool,
+    trust_original_method: bool,
+    /// Open while the identity provider is failing, so that stale decisions are served without
+    /// waiting for it.
+    idp_breaker: CircuitBreaker,
 }
 
+/// How long stale decisions are served without asking the identity provider after it has failed.
+const IDP_BREAKER_DURATION: Duration = Duration::from_secs(10);
+
 impl OIDC {
     pub fn new(
+        http: Client,
         keycloak_base_url: &str,
         client_id: String,
         client_secret: String,
         auth_callback_path: String,
         redis: Redis,
+        token_refresh_window: u64,
     ) -> Result<Self> {
         let base_url = Url::parse(keycloak_base_url)?;
         Ok(Self {
+            http,
+            issuer: base_url.as_str().trim_end_matches('/').to_owned(),
             auth_url: base_url.join("protocol/openid-connect/auth")?,
+            discovery_url: base_url.join(".well-known/openid-configuration")?,
             token_url: base_url.join("protocol/openid-connect/token")?,
             userinfo_url: base_url.join("protocol/openid-connect/userinfo")?,
+            logout_url: base_url.join("protocol/openid-connect/logout")?,
             client_id,
             client_secret,
             auth_callback_path,
+            auth_logout_path: None,
             redis,
+            session_locks: KeyedLock::default(),
+            token_refresh_window,
+            client_policy: ClientPolicy::default(),
+            policy: None,
+            allow_cors_preflight: false,
+            trust_original_method: false,
+            idp_breaker: CircuitBreaker::new(IDP_BREAKER_DURATION),
         })
     }
 
+    #[must_use]
+    pub fn with_logout_path(mut self, auth_logout_path: String) -> Self {
+        self.auth_logout_path = Some(auth_logout_path);
+        self
+    }
+
+    #[must_use]
+    pub fn with_client_policy(mut self, client_policy: ClientPolicy) -> Self {
+        self.client_policy = client_policy;
+        self
+    }
+
+    #[must_use]
+    pub fn with_policy(mut self, policy: Option<Policy>) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_cors_preflight(mut self, allow_cors_preflight: bool) -> Self {
+        self.allow_cors_preflight = allow_cors_preflight;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_original_method(mut self, trust_original_method: bool) -> Self {
+        self.trust_original_method = trust_original_method;
+        self
+    }
+
+    /// See [`Redis::invalidation_listener`].
+    pub fn invalidation_listener(&self) -> Option<impl Future<Output = ()>> {
+        self.redis.invalidation_listener()
+    }
+
+    pub const fn redis(&self) -> &Redis {
+        &self.redis
+    }
+
+    pub const fn client_policy(&self) -> &ClientPolicy {
+        &self.client_policy
+    }
+
+    pub const fn policy(&self) -> Option<&Policy> {
+        self.policy.as_ref()
+    }
+
+    pub const fn allows_cors_preflight(&self) -> bool {
+        self.allow_cors_preflight
+    }
+
+    pub const fn trusts_original_method(&self) -> bool {
+        self.trust_original_method
+    }
+
     pub fn get_callback_url(&self, url: &Url) -> Result<Url> {
         Ok(url.join(&self.auth_callback_path)?)
     }
@@ -54,7 +152,8 @@ impl OIDC {
         )?)
     }
 
-    pub async fn get_token(&self, auth: &AuthType) -> Result<TokenResponse> {
+    #[instrument(name = "idp.token", skip_all, fields(otel.kind = "client"))]
+    pub async fn get_token(&self, auth: &AuthType) -> error::Result<TokenResponse> {
         let mut form = vec![
             ("client_id", self.client_id.as_str()),
             ("client_secret", self.client_secret.as_str()),
@@ -70,27 +169,153 @@ impl OIDC {
                 form.push(("refresh_token", token));
             }
         }
-        Ok(Client::new()
+        let _timer = metrics::idp_timer("token");
+        let response = self
+            .http
             .post(self.token_url.as_str())
+            .headers(telemetry::trace_headers())
             .form(&form)
             .send()
-            .await?
-            .error_for_status()?
-            .json()
-            .await?)
+            .await?;
+        let status = response.status();
+        if status.is_client_error() && status != StatusCode::TOO_MANY_REQUESTS {
+            return Err(token_error(status, &response.text().await?));
+        }
+        Ok(response.error_for_status()?.json::<TokenResponse>().await?)
     }
 
-    pub async fn get_userinfo(&self, access_token: &str) -> Result<UserInfo> {
-        Ok(Client::new()
+    #[instrument(name = "idp.userinfo", skip_all, fields(otel.kind = "client"))]
+    pub async fn get_userinfo(&self, access_token: &str) -> error::Result<UserInfo> {
+        let _timer = metrics::idp_timer("userinfo");
+        let response = self
+            .http
             .get(self.userinfo_url.as_str())
+            .headers(telemetry::trace_headers())
             .header("Authorization", format!("Bearer {access_token}"))
             .send()
+            .await?;
+        if response.status() == StatusCode::UNAUTHORIZED {
+            // the access token has been revoked, e.g. because the user has logged out
+            return Err(Error::InvalidSession(eyre!(
+                "userinfo request rejected: {}",
+                response.status()
+            )));
+        }
+        Ok(response.error_for_status()?.json::<UserInfo>().await?)
+    }
+
+    /// End the session at the identity provider, so that the user is not logged in again without
+    /// entering their credentials.
+    #[instrument(name = "idp.logout", skip_all, fields(otel.kind = "client"))]
+    async fn end_idp_session(&self, refresh_token: &str) -> error::Result<()> {
+        let _timer = metrics::idp_timer("logout");
+        self.http
+            .post(self.logout_url.as_str())
+            .headers(telemetry::trace_headers())
+            .form(&[
+                ("client_id", self.client_id.as_str()),
+                ("client_secret", self.client_secret.as_str()),
+                ("refresh_token", refresh_token),
+            ])
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Check whether the identity provider is reachable.
+    #[instrument(name = "idp.discovery", skip_all, fields(otel.kind = "client"))]
+    pub async fn check_idp(&self) -> error::Result<()> {
+        let _timer = metrics::idp_timer("discovery");
+        self.http
+            .get(self.discovery_url.as_str())
+            .headers(telemetry::trace_headers())
+            .send()
+            .await?
+            .error_for_status()
+            // a client error does not mean that the session is invalid here
+            .map_err(|err| Error::Unavailable(Dependency::IdP, err.into()))?;
+        Ok(())
+    }
+
+    /// Fetch the discovery document and return all differences between the issuer and endpoints
+    /// announced by the identity provider and the ones derived from the keycloak base url.
+    pub async fn verify_discovery(&self) -> Result<Vec<String>> {
+        let discovery = self
+            .http
+            .get(self.discovery_url.as_str())
+            .send()
             .await?
-            .json()
-            .await?)
+            .error_for_status()?
+            .json::<Discovery>()
+            .await?;
+        Ok(self.discovery_mismatches(&discovery))
+    }
+
+    fn discovery_mismatches(&self, discovery: &Discovery) -> Vec<String> {
+        [
+            ("issuer", &discovery.issuer, self.issuer.as_str()),
+            (
+                "authorization_endpoint",
+                &discovery.authorization_endpoint,
+                self.auth_url.as_str(),
+            ),
+            (
+                "token_endpoint",
+                &discovery.token_endpoint,
+                self.token_url.as_str(),
+            ),
+            (
+                "userinfo_endpoint",
+                &discovery.userinfo_endpoint,
+                self.userinfo_url.as_str(),
+            ),
+            (
+                "end_session_endpoint",
+                &discovery.end_session_endpoint,
+                self.logout_url.as_str(),
+            ),
+        ]
+        .into_iter()
+        .filter(|(_, announced, expected)| announced != expected)
+        .map(|(name, announced, expected)| format!("{name} is {announced}, expected {expected}"))
+        .collect()
     }
 
-    pub async fn create_session(&self, auth: CodeAuth) -> Result<Session> {
+    /// Check the client credentials by requesting a token using the client credentials grant.
+    pub async fn check_client_credentials(&self) -> Result<()> {
+        let response = self
+            .http
+            .post(self.token_url.as_str())
+            .form(&[
+                ("grant_type", "client_credentials"),
+                ("client_id", self.client_id.as_str()),
+                ("client_secret", self.client_secret.as_str()),
+            ])
+            .send()
+            .await?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        let body = response.text().await?;
+        match serde_json::from_str::<TokenError>(&body) {
+            // the client has been authenticated, but service accounts are not enabled for it
+            Ok(err) if err.error == "unauthorized_client" => Ok(()),
+            Ok(err) => bail!("{status}: {}", err.error_description.unwrap_or(err.error)),
+            Err(_) => bail!("{status}: {body}"),
+        }
+    }
+
+    pub async fn check_redis(&self) -> error::Result<()> {
+        self.redis.ping().await
+    }
+
+    pub async fn create_session(
+        &self,
+        auth: CodeAuth,
+        client: &ClientInfo,
+    ) -> error::Result<Session> {
         let token = self
             .get_token(&AuthType::Code(auth))
             .await
@@ -99,102 +324,423 @@ impl OIDC {
             .get_userinfo(&token.access_token)
             .await
             .wrap_err("could not fetch user info")?;
-        let session_id: String = rand::thread_rng()
-            .sample_iter(&Alphanumeric)
-            .take(64)
-            .map(char::from)
-            .collect();
+        let session_id = generate_session_id();
         self.redis
-            .set_token(session_id.as_str(), &token)
+            .create_session(&session_key(&session_id), &token, &userinfo, client)
             .await
-            .wrap_err("could not store token in redis")?;
+            .wrap_err("could not store session in redis")?;
         Ok(Session {
             session_id,
             userinfo,
         })
     }
 
-    pub async fn get_session(&self, session_id: &str) -> Result<Session> {
-        let token = self
+    /// End a session both at the identity provider and in redis. An old session id that is still
+    /// valid after a rotation ends the current session. Returns `false` if the session does not
+    /// exist or has expired already.
+    pub async fn logout(&self, session_id: &str) -> error::Result<bool> {
+        let alias = session_key(session_id);
+        let key = self
             .redis
-            .get_token(session_id)
+            .resolve_session_key(&alias)
             .await
-            .wrap_err("could not fetch token from redis")?;
-        let userinfo = match self.get_userinfo(&token.access_token).await {
-            Ok(userinfo) => userinfo,
-            Err(err) => {
-                debug!("could not use access token to fetch userinfo: {:?}", err);
-                let token_response = self
-                    .get_token(&AuthType::RefreshToken(token.refresh_token))
-                    .await
-                    .wrap_err("could not refresh access token")?;
-                let userinfo = self
-                    .get_userinfo(&token_response.access_token)
-                    .await
-                    .wrap_err("could not use fresh access token to fetch userinfo")?;
-                self.redis
-                    .set_token(session_id, &token_response)
-                    .await
-                    .wrap_err("could not store token in redis")?;
-                userinfo
+            .wrap_err("could not resolve session key")?;
+        let token = match self.redis.get_tokens(&key).await {
+            Ok(token) => token,
+            // expired sessions have been deleted already
+            Err(Error::InvalidSession(err)) => {
+                debug!("session is not valid anymore: {err:?}");
+                None
             }
+            Err(err) => return Err(err.wrap_err("could not fetch token from redis")),
         };
+        if let Some(token) = &token {
+            if let Err(err) = self.end_idp_session(&token.refresh_token).await {
+                // the session is removed anyway, but the user may be logged in again without
+                // having to enter their credentials
+                warn!("could not end session at the identity provider: {err}");
+            }
+        }
+        // sessions with unreadable tokens are removed as well
+        let deleted = self
+            .redis
+            .delete_session(&key)
+            .await
+            .wrap_err("could not delete session from redis")?;
+        if key != alias {
+            self.redis
+                .delete_session(&alias)
+                .await
+                .wrap_err("could not delete session alias from redis")?;
+        }
+        Ok(token.is_some() && deleted)
+    }
+
+    /// Fetch the userinfo of a session, refreshing its tokens if necessary. `key` is the key of
+    /// the session, which differs from the key derived from `session_id` if the session id has
+    /// been rotated.
+    pub async fn get_session(&self, session_id: &str, key: &str) -> error::Result<Session> {
+        let token = match self
+            .redis
+            .get_tokens(key)
+            .await
+            .wrap_err("could not fetch token from redis")?
+        {
+            Some(token) => token,
+            None => self
+                .redis
+                .migrate_legacy_session(session_id)
+                .await
+                .wrap_err("could not migrate legacy session")?
+                .ok_or_else(|| Error::invalid_session("session not found"))?,
+        };
+
+        let expiring = token
+            .expires_at
+            .is_none_or(|expires_at| expires_at <= unix_time() + self.token_refresh_window);
+        if !expiring {
+            match self.get_userinfo(&token.access_token).await {
+                Ok(userinfo) => {
+                    return Ok(Session {
+                        session_id: session_id.into(),
+                        userinfo,
+                    })
+                }
+                Err(Error::InvalidSession(err)) => {
+                    debug!("access token has been rejected: {:?}", err);
+                }
+                Err(err) => return Err(err.wrap_err("could not fetch userinfo")),
+            }
+        }
+
+        let userinfo = self.refresh_session(key, token.refresh_token).await?;
         Ok(Session {
             session_id: session_id.into(),
             userinfo,
         })
     }
 
-    pub async fn is_authorized(&self, session_id: &str, role: &str) -> Result<bool> {
-        Ok(
+    /// Refresh the tokens of a session and fetch the current userinfo.
+    async fn refresh_session(&self, key: &str, refresh_token: String) -> error::Result<UserInfo> {
+        let result = self.refresh_session_inner(key, refresh_token).await;
+        metrics::token_refresh(result.is_ok());
+        result
+    }
+
+    async fn refresh_session_inner(
+        &self,
+        key: &str,
+        refresh_token: String,
+    ) -> error::Result<UserInfo> {
+        let token_response = match self.get_token(&AuthType::RefreshToken(refresh_token)).await {
+            Ok(token_response) => token_response,
+            Err(err @ Error::InvalidSession(_)) => {
+                // the refresh token has been revoked or has expired
+                self.redis
+                    .delete_session(key)
+                    .await
+                    .wrap_err("could not delete session from redis")?;
+                return Err(err.wrap_err("could not refresh access token"));
+            }
+            Err(err) => return Err(err.wrap_err("could not refresh access token")),
+        };
+        let userinfo = self
+            .get_userinfo(&token_response.access_token)
+            .await
+            .wrap_err("could not use fresh access token to fetch userinfo")?;
+        self.redis
+            .update_session(key, &token_response, &userinfo)
+            .await
+            .wrap_err("could not store session in redis")?;
+        Ok(userinfo)
+    }
+
+    /// Refresh the tokens of all recently used sessions that are about to expire.
+    pub async fn refresh_expiring_sessions(&self) -> error::Result<()> {
+        let keys = self
+            .redis
+            .expiring_sessions(unix_time() + self.token_refresh_window)
+            .await
+            .wrap_err("could not fetch expiring sessions from redis")?;
+        for key in keys {
+            let result = self
+                .with_session_lock(&key, async {
+                    // the session might have been refreshed while we were waiting for the lock
+                    let Some(token) = self.redis.get_tokens(&key).await? else {
+                        return Ok(());
+                    };
+                    if token.expires_at.is_some_and(|expires_at| {
+                        expires_at > unix_time() + self.token_refresh_window
+                    }) {
+                        return Ok(());
+                    }
+                    self.refresh_session(&key, token.refresh_token).await?;
+                    Ok(())
+                })
+                .await;
+            if let Err(err) = result {
+                warn!("could not refresh session: {err}");
+            }
+        }
+        Ok(())
+    }
+
+    /// Run `f` while holding the lock of a session.
+    ///
+    /// Only one request per session may refresh tokens or fetch userinfo at a time, so that
+    /// concurrent requests don't race each other when refresh tokens are rotated.
+    async fn with_session_lock<T>(
+        &self,
+        key: &str,
+        f: impl Future<Output = error::Result<T>>,
+    ) -> error::Result<T> {
+        let _guard = self.session_locks.lock(key).await;
+        let lock = self
+            .redis
+            .lock_session(key)
+            .await
+            .wrap_err("could not acquire session lock")?;
+        let result = f.await;
+        if let Some(lock) = lock {
+            if let Err(err) = self.redis.unlock_session(lock).await {
+                warn!("could not release session lock: {err}");
+            }
+        }
+        result
+    }
+
+    pub async fn is_authorized(
+        &self,
+        session_id: &str,
+        requirement: &Requirement,
+        client: &ClientInfo,
+    ) -> error::Result<Decision> {
+        let SessionState {
+            cache,
+            identity,
+            key,
+            rotate,
+        } = self
+            .redis
+            .get_session_cache(&session_key(session_id), requirement.key())
+            .await
+            .wrap_err("could not get session cache from redis")?;
+        if !self.client_policy.allows(&identity.client, client) {
+            warn!(
+                "rejecting session created by another client: {:?} != {:?}",
+                identity.client, client
+            );
+            return Err(Error::invalid_session(
+                "session is bound to a different client",
+            ));
+        }
+        let mut decision = match cache {
+            SessionCache::Allowed => Decision::cached(true, identity),
+            SessionCache::Forbidden => Decision::cached(false, identity),
+            // don't queue up behind other requests that are waiting for a failing identity provider
+            SessionCache::Stale if self.idp_breaker.is_open() => {
+                stale_decision("identity provider failed recently", identity)
+            }
+            // requests waiting for the lock will find the result in the session cache afterwards
+            SessionCache::NotCached | SessionCache::Stale => {
+                self.check_authorization(session_id, key.clone(), requirement)
+                    .await?
+            }
+        };
+        if rotate && decision.allowed {
+            decision.new_session_id = self.rotate_session(&key).await;
+        }
+        Ok(decision)
+    }
+
+    /// Check the authorization of a session while holding its lock. If the session has been
+    /// rotated by a concurrent request in the meantime, `key` only refers to an alias, so the
+    /// check is repeated with the lock of the new key.
+    async fn check_authorization(
+        &self,
+        session_id: &str,
+        mut key: String,
+        requirement: &Requirement,
+    ) -> error::Result<Decision> {
+        loop {
             match self
-                .redis
-                .get_session_cache(session_id, role)
-                .await
-                .wrap_err("could not get session cache from redis")?
+                .with_session_lock(
+                    &key,
+                    self.check_authorization_locked(session_id, &key, requirement),
+                )
+                .await?
             {
-                SessionCache::Allowed => true,
-                SessionCache::Forbidden => false,
-                SessionCache::NotCached => {
-                    let result: bool = self
-                        .get_session(session_id)
-                        .await
-                        .wrap_err("could not fetch session data")?
-                        .userinfo
-                        .roles
-                        .contains(&role.into());
-                    self.redis
-                        .update_session_cache(
-                            session_id,
-                            role,
-                            if result {
-                                &SessionCache::Allowed
-                            } else {
-                                &SessionCache::Forbidden
-                            },
-                        )
-                        .await
-                        .wrap_err("could not update redis session cache")?;
-                    result
+                ControlFlow::Break(decision) => return Ok(decision),
+                ControlFlow::Continue(rotated_key) => key = rotated_key,
+            }
+        }
+    }
+
+    /// Returns the key of the session instead of a decision if it differs from `key`.
+    async fn check_authorization_locked(
+        &self,
+        session_id: &str,
+        key: &str,
+        requirement: &Requirement,
+    ) -> error::Result<ControlFlow<Decision, String>> {
+        let SessionState {
+            cache,
+            identity: cached_identity,
+            key: resolved_key,
+            ..
+        } = self
+            .redis
+            .get_session_cache(key, requirement.key())
+            .await
+            .wrap_err("could not get session cache from redis")?;
+        if resolved_key != key {
+            debug!("session has been rotated while waiting for the lock");
+            return Ok(ControlFlow::Continue(resolved_key));
+        }
+        Ok(ControlFlow::Break(match cache {
+            SessionCache::Allowed => Decision::cached(true, cached_identity),
+            SessionCache::Forbidden => Decision::cached(false, cached_identity),
+            // the request holding the lock before us has found the identity provider failing
+            SessionCache::Stale if self.idp_breaker.is_open() => {
+                stale_decision("identity provider failed recently", cached_identity)
+            }
+            SessionCache::NotCached | SessionCache::Stale => {
+                let session = match self.get_session(session_id, key).await {
+                    Ok(session) => {
+                        self.idp_breaker.reset();
+                        session
+                    }
+                    Err(Error::Unavailable(Dependency::IdP, err)) => {
+                        self.idp_breaker.trip();
+                        if matches!(cache, SessionCache::Stale) {
+                            return Ok(ControlFlow::Break(stale_decision(err, cached_identity)));
+                        }
+                        return Err(Error::Unavailable(Dependency::IdP, err)
+                            .wrap_err("could not fetch session data"));
+                    }
+                    Err(err) => return Err(err.wrap_err("could not fetch session data")),
+                };
+                let allowed = requirement.is_satisfied(&session.userinfo);
+                let identity = Arc::new(Identity {
+                    client: cached_identity.client.clone(),
+                    ..Identity::from(&session.userinfo)
+                });
+                self.redis
+                    .update_session_cache(
+                        key,
+                        requirement.key(),
+                        if allowed {
+                            &SessionCache::Allowed
+                        } else {
+                            &SessionCache::Forbidden
+                        },
+                        &identity,
+                    )
+                    .await
+                    .wrap_err("could not update redis session cache")?;
+                Decision {
+                    allowed,
+                    cache_hit: false,
+                    identity,
+                    new_session_id: None,
                 }
-            },
-        )
+            }
+        }))
+    }
+
+    /// Issue a new id for a session. Failures are only logged, as the old id stays valid.
+    async fn rotate_session(&self, key: &str) -> Option<String> {
+        let result = self
+            .with_session_lock(key, async {
+                let session_id = generate_session_id();
+                let rotated = self
+                    .redis
+                    .rotate_session(key, &session_key(&session_id))
+                    .await?;
+                Ok(rotated.then_some(session_id))
+            })
+            .await;
+        match result {
+            Ok(session_id) => {
+                if session_id.is_some() {
+                    debug!("session id rotated");
+                }
+                session_id
+            }
+            Err(err) => {
+                warn!("could not rotate session id: {err}");
+                None
+            }
+        }
     }
 }
 
+fn stale_decision(reason: impl fmt::Display, identity: Arc<Identity>) -> Decision {
+    warn!("identity provider unavailable, using stale decision: {reason}");
+    metrics::stale_decision();
+    Decision::cached(true, identity)
+}
+
+/// Only an invalid grant (i.e. an expired or revoked refresh token or authorization code)
+/// invalidates the session. Errors like `invalid_client` or `unauthorized_client` are caused by a
+/// misconfiguration and must not log out users.
+fn token_error(status: StatusCode, body: &str) -> Error {
+    match serde_json::from_str::<TokenError>(body) {
+        Ok(err) if status == StatusCode::BAD_REQUEST && err.error == "invalid_grant" => {
+            Error::InvalidSession(eyre!(
+                "{status}: {}",
+                err.error_description.unwrap_or(err.error)
+            ))
+        }
+        Ok(err) => Error::Internal(eyre!(
+            "{status}: {}",
+            err.error_description.unwrap_or(err.error)
+        )),
+        Err(_) => Error::Internal(eyre!("{status}: {body}")),
+    }
+}
+
+fn generate_session_id() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(64)
+        .map(char::from)
+        .collect()
+}
+
+/// Result of an authorization check.
 #[derive(Debug)]
+pub struct Decision {
+    pub allowed: bool,
+    /// Whether the decision has been taken from the session cache.
+    pub cache_hit: bool,
+    pub identity: Arc<Identity>,
+    /// New id of the session, if it has been rotated.
+    pub new_session_id: Option<String>,
+}
+
+impl Decision {
+    const fn cached(allowed: bool, identity: Arc<Identity>) -> Self {
+        Self {
+            allowed,
+            cache_hit: true,
+            identity,
+            new_session_id: None,
+        }
+    }
+}
+
 pub struct CodeAuth {
     pub code: String,
     pub callback_url: Url,
 }
 
-#[derive(Debug)]
 pub enum AuthType {
     Code(CodeAuth),
     RefreshToken(String),
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize)]
 pub struct TokenResponse {
     pub access_token: String,
     pub refresh_token: String,
@@ -202,14 +748,336 @@ pub struct TokenResponse {
     pub refresh_expires_in: u64,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize)]
+pub struct Discovery {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub userinfo_endpoint: String,
+    pub end_session_endpoint: String,
+}
+
+#[derive(Deserialize)]
+struct TokenError {
+    error: String,
+    error_description: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
 pub struct UserInfo {
+    #[serde(default)]
+    pub sub: Option<String>,
+    #[serde(default)]
+    pub preferred_username: Option<String>,
     #[serde(default)]
     pub roles: Vec<String>,
+    #[serde(default)]
+    pub groups: Vec<String>,
+}
+
+impl From<&UserInfo> for Identity {
+    fn from(userinfo: &UserInfo) -> Self {
+        Self {
+            sub: userinfo.sub.clone(),
+            username: userinfo.preferred_username.clone(),
+            client: ClientInfo::default(),
+        }
+    }
 }
 
-#[derive(Debug)]
 pub struct Session {
     pub session_id: String,
     pub userinfo: UserInfo,
 }
+
+impl fmt::Debug for CodeAuth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CodeAuth")
+            .field("code", &Redacted)
+            .field("callback_url", &self.callback_url)
+            .finish()
+    }
+}
+
+impl fmt::Debug for AuthType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Code(auth) => f.debug_tuple("Code").field(auth).finish(),
+            Self::RefreshToken(_) => f.debug_tuple("RefreshToken").field(&Redacted).finish(),
+        }
+    }
+}
+
+impl fmt::Debug for TokenResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TokenResponse")
+            .field("access_token", &Redacted)
+            .field("refresh_token", &Redacted)
+            .field("expires_in", &self.expires_in)
+            .field("refresh_expires_in", &self.refresh_expires_in)
+            .finish()
+    }
+}
+
+impl fmt::Debug for Session {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Session")
+            .field("session_id", &Redacted)
+            .field("userinfo", &self.userinfo)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use std::{env, net::TcpListener, time::Duration};
+
+    use axum::{
+        routing::{get, post},
+        Json, Router, Server,
+    };
+    use serde_json::json;
+
+    use super::*;
+    use crate::redis::{SessionLimits, SessionRotation};
+
+    /// Start an identity provider that accepts every token and grants the `admin` and `viewer`
+    /// roles. Token requests are slow, so that concurrent requests overlap.
+    fn fake_idp() -> String {
+        let app = Router::new()
+            .route(
+                "/protocol/openid-connect/token",
+                post(|| async {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    Json(json!({
+                        "access_token": "access",
+                        "refresh_token": "refresh",
+                        "expires_in": 300,
+                        "refresh_expires_in": 1800,
+                    }))
+                }),
+            )
+            .route(
+                "/protocol/openid-connect/userinfo",
+                get(|| async { Json(json!({"sub": "f3b2c1", "roles": ["admin", "viewer"]})) }),
+            )
+            .route("/protocol/openid-connect/logout", post(|| async {}));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(
+            Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+        url
+    }
+
+    /// An instance that rotates session ids on every allowed request.
+    fn instance(redis_url: &str, idp_url: &str, limits: SessionLimits) -> OIDC {
+        let redis = Redis::new(redis_url, None, 60, 10, None, None, Duration::from_secs(5))
+            .unwrap()
+            .with_limits(limits)
+            .with_rotation(SessionRotation {
+                interval: Some(0),
+                on_refresh: false,
+                grace_period: 30,
+            });
+        OIDC::new(
+            Client::new(),
+            idp_url,
+            "my_oidc_client".to_owned(),
+            "secret".to_owned(),
+            "/_auth/callback".to_owned(),
+            redis,
+            30,
+        )
+        .unwrap()
+    }
+
+    /// Store a session whose access token has expired already. Returns the session id.
+    async fn create_session(oidc: &OIDC) -> String {
+        let session_id = generate_session_id();
+        let token = TokenResponse {
+            access_token: "expired".to_owned(),
+            refresh_token: "refresh".to_owned(),
+            expires_in: 0,
+            refresh_expires_in: 1800,
+        };
+        let userinfo = UserInfo {
+            sub: Some("f3b2c1".to_owned()),
+            preferred_username: None,
+            roles: vec!["admin".to_owned(), "viewer".to_owned()],
+            groups: vec![],
+        };
+        oidc.redis
+            .create_session(
+                &session_key(&session_id),
+                &token,
+                &userinfo,
+                &ClientInfo::default(),
+            )
+            .await
+            .unwrap();
+        session_id
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a redis server at REDIS_URL"]
+    async fn test_logout() {
+        let redis_url = env::var("REDIS_URL").unwrap_or_else(|_| "redis://localhost".to_owned());
+        let idp_url = fake_idp();
+        let oidc = instance(&redis_url, &idp_url, SessionLimits::default());
+        let client = ClientInfo::default();
+        let viewer = Requirement::role("viewer".to_owned());
+
+        // an old session id within the grace period ends the current session
+        let session_id = create_session(&oidc).await;
+        let decision = oidc.is_authorized(&session_id, &viewer, &client).await;
+        assert!(decision.unwrap().allowed);
+        let key = oidc
+            .redis
+            .resolve_session_key(&session_key(&session_id))
+            .await
+            .unwrap();
+        assert_ne!(key, session_key(&session_id));
+        assert!(oidc.logout(&session_id).await.unwrap());
+        assert!(oidc.redis.get_tokens(&key).await.unwrap().is_none());
+        assert!(!oidc.logout(&session_id).await.unwrap());
+
+        // expired sessions are logged out without an error
+        let expired_id = create_session(&oidc).await;
+        let expired = instance(
+            &redis_url,
+            &idp_url,
+            SessionLimits {
+                idle_timeout: None,
+                max_age: Some(0),
+            },
+        );
+        assert!(!expired.logout(&expired_id).await.unwrap());
+        let tokens = oidc.redis.get_tokens(&session_key(&expired_id)).await;
+        assert!(tokens.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a redis server at REDIS_URL"]
+    async fn test_concurrent_refresh_with_rotation() {
+        let redis_url = env::var("REDIS_URL").unwrap_or_else(|_| "redis://localhost".to_owned());
+        let idp_url = fake_idp();
+        let (first, second) = (
+            instance(&redis_url, &idp_url, SessionLimits::default()),
+            instance(&redis_url, &idp_url, SessionLimits::default()),
+        );
+        let session_id = create_session(&first).await;
+
+        // Both requests use the old cookie and need a refresh. The second one waits for the lock
+        // while the first one refreshes the tokens and rotates the session id.
+        let client = ClientInfo::default();
+        let viewer = Requirement::role("viewer".to_owned());
+        let admin = Requirement::role("admin".to_owned());
+        let (viewer, admin) = tokio::join!(
+            first.is_authorized(&session_id, &viewer, &client),
+            second.is_authorized(&session_id, &admin, &client),
+        );
+        assert!(viewer.unwrap().allowed);
+        assert!(admin.unwrap().allowed);
+
+        first.redis.delete_user_sessions("f3b2c1").await.unwrap();
+    }
+
+    #[test]
+    fn test_token_error() {
+        let error = |status, body| token_error(StatusCode::from_u16(status).unwrap(), body);
+        assert!(matches!(
+            error(
+                400,
+                r#"{"error":"invalid_grant","error_description":"Token is not active"}"#
+            ),
+            Error::InvalidSession(_)
+        ));
+        assert!(matches!(
+            error(401, r#"{"error":"invalid_client"}"#),
+            Error::Internal(_)
+        ));
+        assert!(matches!(
+            error(400, r#"{"error":"unauthorized_client"}"#),
+            Error::Internal(_)
+        ));
+        assert!(matches!(
+            error(401, r#"{"error":"invalid_grant"}"#),
+            Error::Internal(_)
+        ));
+        assert!(matches!(error(400, "not json"), Error::Internal(_)));
+    }
+
+    #[test]
+    fn test_discovery_mismatches() {
+        let redis = Redis::new(
+            "redis://localhost",
+            None,
+            60,
+            10,
+            None,
+            None,
+            Duration::ZERO,
+        );
+        let oidc = OIDC::new(
+            Client::new(),
+            "http://id.domain.de/realms/my_realm/",
+            "my_oidc_client".to_owned(),
+            "secret".to_owned(),
+            "/_auth/callback".to_owned(),
+            redis.unwrap(),
+            30,
+        )
+        .unwrap();
+        let realm = "http://id.domain.de/realms/my_realm";
+        let mut discovery = Discovery {
+            issuer: realm.to_owned(),
+            authorization_endpoint: format!("{realm}/protocol/openid-connect/auth"),
+            token_endpoint: format!("{realm}/protocol/openid-connect/token"),
+            userinfo_endpoint: format!("{realm}/protocol/openid-connect/userinfo"),
+            end_session_endpoint: format!("{realm}/protocol/openid-connect/logout"),
+        };
+        assert!(oidc.discovery_mismatches(&discovery).is_empty());
+
+        discovery.issuer = "https://sso.domain.de/realms/my_realm".to_owned();
+        assert_eq!(
+            oidc.discovery_mismatches(&discovery),
+            ["issuer is https://sso.domain.de/realms/my_realm, expected http://id.domain.de/realms/my_realm"]
+        );
+    }
+
+    #[test]
+    fn test_debug_redacted() {
+        let token = TokenResponse {
+            access_token: "secret-access-token".to_owned(),
+            refresh_token: "secret-refresh-token".to_owned(),
+            expires_in: 300,
+            refresh_expires_in: 1800,
+        };
+        let token_debug = format!("{token:?}");
+        assert!(!token_debug.contains("secret"));
+        assert!(token_debug.contains("expires_in: 300"));
+
+        assert_eq!(
+            format!(
+                "{:?}",
+                AuthType::RefreshToken("secret-refresh-token".to_owned())
+            ),
+            "RefreshToken(<redacted>)"
+        );
+
+        let session = Session {
+            session_id: "secret-session-id".to_owned(),
+            userinfo: UserInfo {
+                sub: None,
+                preferred_username: None,
+                roles: vec![],
+                groups: vec![],
+            },
+        };
+        assert!(!format!("{session:?}").contains("secret"));
+    }
+}
@@ -0,0 +1,444 @@
+use std::path::Path;
+
+use config::{File, FileFormat};
+use eyre::{bail, ensure, Result, WrapErr};
+use percent_encoding::percent_decode_str;
+use regex::Regex;
+use serde::Deserialize;
+use tracing::debug;
+
+use crate::oidc::UserInfo;
+
+/// Roles or groups a user needs to be granted access. Having any of them is sufficient, and a
+/// requirement without roles and groups is satisfied by every logged in user.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Requirement {
+    roles: Vec<String>,
+    groups: Vec<String>,
+    key: String,
+}
+
+impl Requirement {
+    pub fn new(roles: Vec<String>, groups: Vec<String>) -> Self {
+        let key = match (roles.as_slice(), groups.as_slice()) {
+            // a single role is identified by its name, like with the `role` parameter
+            ([role], []) => role.clone(),
+            _ => format!(
+                "any({})",
+                roles
+                    .iter()
+                    .cloned()
+                    .chain(groups.iter().map(|group| format!("group:{group}")))
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ),
+        };
+        Self { roles, groups, key }
+    }
+
+    pub fn role(role: String) -> Self {
+        Self::new(vec![role], vec![])
+    }
+
+    /// Satisfied by every logged in user.
+    pub fn authenticated() -> Self {
+        Self::new(vec![], vec![])
+    }
+
+    /// Identifies the requirement in the decision cache and in logs.
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub fn is_satisfied(&self, userinfo: &UserInfo) -> bool {
+        (self.roles.is_empty() && self.groups.is_empty())
+            || self.roles.iter().any(|role| userinfo.roles.contains(role))
+            || self
+                .groups
+                .iter()
+                .any(|group| userinfo.groups.contains(group))
+    }
+}
+
+/// How a request is authorized.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Access {
+    /// A valid session that satisfies the requirement is needed.
+    Required(Requirement),
+    /// Anonymous requests are allowed, but users with a valid session are identified.
+    Optional,
+    /// Anonymous requests are allowed and the session is not looked up at all.
+    Public,
+}
+
+impl Access {
+    pub const fn requirement(&self) -> Option<&Requirement> {
+        match self {
+            Self::Required(requirement) => Some(requirement),
+            Self::Optional | Self::Public => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    #[default]
+    Required,
+    Optional,
+    Public,
+}
+
+/// Ordered rules that determine the access of a request if neither the `role` nor the `mode`
+/// parameter is given. The first matching rule wins.
+#[derive(Debug)]
+pub struct Policy {
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug)]
+struct Rule {
+    host: Option<String>,
+    path: PathPattern,
+    methods: Vec<String>,
+    access: Access,
+}
+
+#[derive(Debug)]
+enum PathPattern {
+    Any,
+    Prefix(String),
+    Regex(Regex),
+}
+
+#[derive(Deserialize)]
+struct PolicyFile {
+    #[serde(default)]
+    rules: Vec<RuleConfig>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleConfig {
+    host: Option<String>,
+    path: Option<String>,
+    path_regex: Option<String>,
+    path_glob: Option<String>,
+    #[serde(default)]
+    methods: Vec<String>,
+    #[serde(default)]
+    mode: Mode,
+    #[serde(default)]
+    roles: Vec<String>,
+    #[serde(default)]
+    groups: Vec<String>,
+}
+
+impl Policy {
+    pub fn load(path: &Path) -> Result<Self> {
+        let file: PolicyFile = config::Config::builder()
+            .add_source(File::from(path).format(FileFormat::Toml))
+            .build()?
+            .try_deserialize()
+            .wrap_err_with(|| format!("could not load policy file {}", path.display()))?;
+        Self::new(file)
+    }
+
+    fn new(file: PolicyFile) -> Result<Self> {
+        let rules = file
+            .rules
+            .into_iter()
+            .enumerate()
+            .map(|(i, rule)| Rule::new(rule).wrap_err_with(|| format!("invalid rule #{}", i + 1)))
+            .collect::<Result<_>>()?;
+        Ok(Self { rules })
+    }
+
+    /// Find the access of the first rule that matches the request. `path` is normalized first,
+    /// so that e.g. `/%61dmin` is treated like `/admin`. Paths that can't be normalized
+    /// unambiguously (see [`normalize_path`]) don't match any rule.
+    pub fn access(&self, host: &str, path: &str, method: &str) -> Option<&Access> {
+        let Some(path) = normalize_path(path) else {
+            debug!("ambiguous path {path:?} is denied");
+            return None;
+        };
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(host, &path, method))
+            .map(|rule| &rule.access)
+    }
+}
+
+impl Rule {
+    fn new(rule: RuleConfig) -> Result<Self> {
+        let path = match (rule.path, rule.path_regex, rule.path_glob) {
+            (None, None, None) => PathPattern::Any,
+            (Some(prefix), None, None) => {
+                ensure!(prefix.starts_with('/'), "path must start with /");
+                PathPattern::Prefix(prefix)
+            }
+            (None, Some(regex), None) => PathPattern::Regex(Regex::new(&regex)?),
+            (None, None, Some(glob)) => {
+                ensure!(glob.starts_with('/'), "path_glob must start with /");
+                PathPattern::Regex(glob_to_regex(&glob)?)
+            }
+            _ => bail!("only one of path, path_regex and path_glob may be set"),
+        };
+        let access = match rule.mode {
+            Mode::Required => Access::Required(Requirement::new(rule.roles, rule.groups)),
+            Mode::Optional | Mode::Public => {
+                ensure!(
+                    rule.roles.is_empty() && rule.groups.is_empty(),
+                    "roles and groups can only be set for required rules"
+                );
+                if matches!(rule.mode, Mode::Public) {
+                    Access::Public
+                } else {
+                    Access::Optional
+                }
+            }
+        };
+        Ok(Self {
+            host: rule.host.map(|host| host.to_ascii_lowercase()),
+            path,
+            methods: rule
+                .methods
+                .iter()
+                .map(|method| method.to_ascii_uppercase())
+                .collect(),
+            access,
+        })
+    }
+
+    fn matches(&self, host: &str, path: &str, method: &str) -> bool {
+        let host_matches = self.host.as_deref().is_none_or(|pattern| {
+            let host = host.to_ascii_lowercase();
+            pattern
+                .strip_prefix("*.")
+                .map_or(host == pattern, |domain| {
+                    host.strip_suffix(domain)
+                        .is_some_and(|sub| sub.len() > 1 && sub.ends_with('.'))
+                })
+        });
+        let path_matches = match &self.path {
+            PathPattern::Any => true,
+            PathPattern::Prefix(prefix) => path.strip_prefix(prefix.as_str()).is_some_and(|rest| {
+                prefix.ends_with('/') || rest.is_empty() || rest.starts_with('/')
+            }),
+            PathPattern::Regex(regex) => regex.is_match(path),
+        };
+        let method_matches = self.methods.is_empty()
+            || self
+                .methods
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(method));
+        host_matches && path_matches && method_matches
+    }
+}
+
+/// Decode percent-encoded characters and merge repeated slashes.
+///
+/// Returns `None` for paths with `.` or `..` segments, backslashes or percent-encoded slashes,
+/// since the upstream service may resolve them differently (e.g. `/admin%2F..%2Fpublic` is
+/// `/admin%2F..%2Fpublic` for nginx, but may be `/public` or `/admin/../public` upstream).
+fn normalize_path(path: &str) -> Option<String> {
+    let mut segments = Vec::new();
+    for segment in path.split('/') {
+        let segment = percent_decode_str(segment).decode_utf8_lossy();
+        if matches!(&*segment, "." | "..") || segment.contains(['/', '\\']) {
+            return None;
+        }
+        if !segment.is_empty() {
+            segments.push(segment);
+        }
+    }
+    let mut normalized = format!("/{}", segments.join("/"));
+    if !segments.is_empty() && path.ends_with('/') {
+        normalized.push('/');
+    }
+    Some(normalized)
+}
+
+/// `*` matches within a path segment, `**` across segments and `?` a single character.
+fn glob_to_regex(glob: &str) -> Result<Regex> {
+    let mut pattern = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.next_if_eq(&'*').is_some() => pattern.push_str(".*"),
+            '*' => pattern.push_str("[^/]*"),
+            '?' => pattern.push_str("[^/]"),
+            c => pattern.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    pattern.push('$');
+    Ok(Regex::new(&pattern)?)
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    fn rule(
+        host: Option<&str>,
+        path: Option<&str>,
+        methods: &[&str],
+        roles: &[&str],
+    ) -> RuleConfig {
+        RuleConfig {
+            host: host.map(ToOwned::to_owned),
+            path: path.map(ToOwned::to_owned),
+            path_regex: None,
+            path_glob: None,
+            methods: methods.iter().map(|&x| x.to_owned()).collect(),
+            mode: Mode::Required,
+            roles: roles.iter().map(|&x| x.to_owned()).collect(),
+            groups: vec![],
+        }
+    }
+
+    #[test]
+    fn test_normalize_path() {
+        assert_eq!(normalize_path("/").as_deref(), Some("/"));
+        assert_eq!(normalize_path("/admin").as_deref(), Some("/admin"));
+        assert_eq!(normalize_path("/admin/").as_deref(), Some("/admin/"));
+        assert_eq!(
+            normalize_path("//admin//users").as_deref(),
+            Some("/admin/users")
+        );
+        assert_eq!(normalize_path("/%61dmin/x").as_deref(), Some("/admin/x"));
+        assert_eq!(normalize_path("/a..b/.x").as_deref(), Some("/a..b/.x"));
+        assert_eq!(normalize_path("/public/../admin"), None);
+        assert_eq!(normalize_path("/public/%2e%2e/admin"), None);
+        assert_eq!(normalize_path("/admin/./x"), None);
+        assert_eq!(normalize_path("/admin%2F..%2Fpublic/x"), None);
+        assert_eq!(normalize_path("/admin%2fx"), None);
+        assert_eq!(normalize_path("/admin\\x"), None);
+        assert_eq!(normalize_path("/admin%5Cx"), None);
+    }
+
+    #[test]
+    fn test_glob_to_regex() {
+        let glob = glob_to_regex("/files/*/private/**").unwrap();
+        assert!(glob.is_match("/files/alice/private/a/b.txt"));
+        assert!(!glob.is_match("/files/alice/bob/private/a"));
+        assert!(!glob.is_match("/files/alice/public/a"));
+        assert!(glob_to_regex("/v?.json").unwrap().is_match("/v1.json"));
+        assert!(!glob_to_regex("/a.b").unwrap().is_match("/axb"));
+    }
+
+    #[test]
+    fn test_requirement() {
+        assert_eq!(Requirement::role("admin".to_owned()).key(), "admin");
+        let requirement = Requirement::new(vec!["editor".to_owned()], vec!["/staff".to_owned()]);
+        assert_eq!(requirement.key(), "any(editor,group:/staff)");
+        let userinfo = |roles: &[&str], groups: &[&str]| UserInfo {
+            sub: None,
+            preferred_username: None,
+            roles: roles.iter().map(|&x| x.to_owned()).collect(),
+            groups: groups.iter().map(|&x| x.to_owned()).collect(),
+        };
+        assert!(requirement.is_satisfied(&userinfo(&["editor"], &[])));
+        assert!(requirement.is_satisfied(&userinfo(&[], &["/staff"])));
+        assert!(!requirement.is_satisfied(&userinfo(&["viewer"], &["/other"])));
+        assert!(Requirement::new(vec![], vec![]).is_satisfied(&userinfo(&[], &[])));
+    }
+
+    #[test]
+    fn test_policy() {
+        let policy = Policy::new(PolicyFile {
+            rules: vec![
+                rule(Some("*.example.com"), Some("/admin"), &[], &["admin"]),
+                rule(None, Some("/api/"), &["post", "DELETE"], &["editor"]),
+                rule(None, Some("/api/"), &[], &["viewer"]),
+                RuleConfig {
+                    path_glob: Some("/files/*/private/**".to_owned()),
+                    ..rule(None, None, &[], &["owner"])
+                },
+                RuleConfig {
+                    path_regex: Some("^/v[0-9]+/".to_owned()),
+                    ..rule(None, None, &[], &["api"])
+                },
+                RuleConfig {
+                    mode: Mode::Public,
+                    ..rule(None, Some("/.well-known/"), &[], &[])
+                },
+                RuleConfig {
+                    mode: Mode::Optional,
+                    ..rule(None, Some("/home"), &[], &[])
+                },
+            ],
+        })
+        .unwrap();
+        let requirement = |host, path, method| {
+            policy
+                .access(host, path, method)
+                .map(|access| match access {
+                    Access::Required(requirement) => requirement.key().to_owned(),
+                    Access::Optional => "<optional>".to_owned(),
+                    Access::Public => "<public>".to_owned(),
+                })
+        };
+        assert_eq!(
+            requirement("app.example.com", "/admin/users", "GET").as_deref(),
+            Some("admin")
+        );
+        assert_eq!(
+            requirement("app.example.com", "/%61dmin", "GET").as_deref(),
+            Some("admin")
+        );
+        assert_eq!(
+            requirement("app.example.com", "/public/%2e%2e/admin", "GET"),
+            None
+        );
+        assert_eq!(
+            requirement("app.example.com", "/administrator", "GET"),
+            None
+        );
+        assert_eq!(requirement("example.com", "/admin", "GET"), None);
+        assert_eq!(
+            requirement("x", "/api/posts", "POST").as_deref(),
+            Some("editor")
+        );
+        assert_eq!(
+            requirement("x", "/api/posts", "GET").as_deref(),
+            Some("viewer")
+        );
+        assert_eq!(
+            requirement("x", "/api/posts", "PUT").as_deref(),
+            Some("viewer")
+        );
+        assert_eq!(
+            requirement("x", "/files/a/private/b", "GET").as_deref(),
+            Some("owner")
+        );
+        assert_eq!(requirement("x", "/v2/users", "GET").as_deref(), Some("api"));
+        assert_eq!(requirement("x", "/", "GET"), None);
+        assert_eq!(
+            requirement("x", "/.well-known/openid-configuration", "GET").as_deref(),
+            Some("<public>")
+        );
+        assert_eq!(requirement("x", "/.well-known/../admin", "GET"), None);
+        assert_eq!(requirement("x", "/admin%2F..%2F.well-known/x", "GET"), None);
+        assert_eq!(
+            requirement("x", "/home", "GET").as_deref(),
+            Some("<optional>")
+        );
+
+        let invalid = |rule| Policy::new(PolicyFile { rules: vec![rule] }).is_err();
+        assert!(invalid(rule(None, Some("admin"), &[], &[])));
+        assert!(invalid(RuleConfig {
+            path_regex: Some("(".to_owned()),
+            ..rule(None, None, &[], &[])
+        }));
+        assert!(invalid(RuleConfig {
+            path_glob: Some("/a".to_owned()),
+            ..rule(None, Some("/b"), &[], &[])
+        }));
+        assert!(invalid(RuleConfig {
+            mode: Mode::Public,
+            ..rule(None, None, &[], &["admin"])
+        }));
+    }
+}
@@ -1,114 +1,1352 @@
-use eyre::Result;
-use log::warn;
-use redis::{aio::Connection, AsyncCommands, Client};
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-use crate::oidc;
+use futures_util::StreamExt;
+use rand::{distributions::Alphanumeric, Rng};
+use redis::{
+    aio::MultiplexedConnection, from_owned_redis_value, AsyncCommands, Client, FromRedisValue,
+    RedisResult, Script, Value,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{debug, info, instrument, warn};
+
+use crate::{
+    cache::LocalCache,
+    client::ClientInfo,
+    crypto::Cipher,
+    error::{Error, Result},
+    metrics,
+    oidc::{self, UserInfo},
+};
+
+/// Version of the session hash layout. Sessions stored with a different version are ignored.
+const SESSION_VERSION: &str = "1";
+
+/// Delete a lock only if it is still held by us.
+const UNLOCK: &str = r"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    return redis.call('DEL', KEYS[1])
+end
+return 0
+";
+
+/// Seconds after which a cache hit records the use of a session again. Limits the writes to
+/// redis, but means that the last use of a session may lag behind by this much.
+pub const LAST_USED_INTERVAL: u64 = 60;
+
+/// Maximum number of aliases that are followed, as a session id might be rotated again within the
+/// grace period of a previous rotation.
+const MAX_ALIASES: usize = 5;
+
+/// Pub/sub channel used to notify other instances about deleted sessions.
+const INVALIDATION_CHANNEL: &str = "nginx-keycloak:invalidate";
+
+/// Sorted set of session keys scored by the expiry of their access tokens, so that expiring
+/// sessions can be found without scanning all keys.
+const REFRESH_INDEX: &str = "nginx-keycloak:refresh";
+
+/// Set fields of a hash only if the hash exists, so that no stray keys without a TTL are created
+/// for sessions that have expired in the meantime.
+const HSET_EXISTING: &str = r"
+if redis.call('EXISTS', KEYS[1]) == 1 then
+    redis.call('HSET', KEYS[1], unpack(ARGV))
+    return 1
+end
+return 0
+";
+
+/// Move a session hash to a new key and leave a `rotated_to` alias that expires after `ARGV[1]`
+/// seconds at the old key. The remaining arguments are fields to set on the moved session.
+const ROTATE_SESSION: &str = r"
+if redis.call('HEXISTS', KEYS[1], 'version') == 0 then
+    return 0
+end
+redis.call('RENAME', KEYS[1], KEYS[2])
+redis.call('HSET', KEYS[2], unpack(ARGV, 2))
+redis.call('HSET', KEYS[1], 'rotated_to', KEYS[2])
+redis.call('EXPIRE', KEYS[1], ARGV[1])
+return 1
+";
+
+/// Add a session to the index of its user. The index expires together with the longest-living
+/// of its sessions (`ARGV[2]` is `0` for sessions that do not expire).
+const INDEX_SESSION: &str = r"
+local existed = redis.call('EXISTS', KEYS[1])
+redis.call('SADD', KEYS[1], ARGV[1])
+local ttl = tonumber(ARGV[2])
+local current = redis.call('TTL', KEYS[1])
+if ttl == 0 then
+    redis.call('PERSIST', KEYS[1])
+elseif existed == 0 or (current >= 0 and current < ttl) then
+    redis.call('EXPIRE', KEYS[1], ttl)
+end
+";
 
 pub struct Redis {
     client: Client,
+    cipher: Option<Cipher>,
     session_allowed_ttl: u64,
     session_forbidden_ttl: u64,
+    session_grace_period: Option<u64>,
+    local_cache: Option<Arc<DecisionCache>>,
+    session_lock_ttl: Duration,
+    limits: SessionLimits,
+    rotation: SessionRotation,
 }
 
 impl Redis {
     pub fn new(
         redis_url: &str,
+        cipher: Option<Cipher>,
         session_allowed_ttl: u64,
         session_forbidden_ttl: u64,
-    ) -> Result<Self> {
+        session_grace_period: Option<u64>,
+        local_cache: Option<Arc<DecisionCache>>,
+        session_lock_ttl: Duration,
+    ) -> eyre::Result<Self> {
         Ok(Self {
             client: Client::open(redis_url)?,
+            cipher,
             session_allowed_ttl,
             session_forbidden_ttl,
+            session_grace_period,
+            local_cache,
+            session_lock_ttl,
+            limits: SessionLimits::default(),
+            rotation: SessionRotation::default(),
+        })
+    }
+
+    #[must_use]
+    pub const fn with_limits(mut self, limits: SessionLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_rotation(mut self, rotation: SessionRotation) -> Self {
+        self.rotation = rotation;
+        self
+    }
+
+    /// Listen for session invalidations published by other instances and remove the affected
+    /// sessions from the local cache.
+    pub fn invalidation_listener(&self) -> Option<impl Future<Output = ()>> {
+        let client = self.client.clone();
+        let local_cache = self.local_cache.clone()?;
+        Some(async move {
+            loop {
+                let result: redis::RedisResult<()> = async {
+                    let mut pubsub = client.get_async_pubsub().await?;
+                    pubsub.subscribe(INVALIDATION_CHANNEL).await?;
+                    // invalidations might have been missed while we were not subscribed
+                    local_cache.clear();
+                    let mut messages = pubsub.on_message();
+                    while let Some(msg) = messages.next().await {
+                        let session_key: String = msg.get_payload()?;
+                        local_cache.invalidate(&session_key);
+                    }
+                    Ok(())
+                }
+                .await;
+                warn!("lost subscription to session invalidations: {result:?}");
+                local_cache.clear();
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
         })
     }
 
-    async fn get_connection(&self) -> Result<Connection> {
-        Ok(self.client.get_async_connection().await?)
+    async fn get_connection(&self) -> Result<MultiplexedConnection> {
+        Ok(self.client.get_multiplexed_tokio_connection().await?)
+    }
+
+    fn encrypt(&self, aad: &str, value: &str) -> eyre::Result<String> {
+        self.cipher
+            .as_ref()
+            .map_or_else(|| Ok(value.to_owned()), |cipher| cipher.encrypt(value, aad))
+    }
+
+    fn decrypt(&self, aad: &str, value: String) -> Option<String> {
+        match &self.cipher {
+            Some(cipher) => cipher.decrypt(&value, aad),
+            None => Some(value),
+        }
+    }
+
+    fn token_fields(
+        &self,
+        key: &str,
+        access_token: &str,
+        refresh_token: &str,
+    ) -> Result<Vec<(String, String)>> {
+        Ok(vec![
+            (
+                "access_token".to_owned(),
+                self.encrypt(&format!("{key}:access_token"), access_token)?,
+            ),
+            (
+                "refresh_token".to_owned(),
+                self.encrypt(&format!("{key}:refresh_token"), refresh_token)?,
+            ),
+        ])
+    }
+
+    /// Token fields of a session hash after the session has been created or refreshed.
+    fn token_response_fields(
+        &self,
+        key: &str,
+        token: &oidc::TokenResponse,
+    ) -> Result<Vec<(String, String)>> {
+        let now = unix_time();
+        let mut fields = vec![
+            ("refreshed_at".to_owned(), now.to_string()),
+            (
+                "access_token_expires_at".to_owned(),
+                (now + token.expires_in).to_string(),
+            ),
+        ];
+        fields.extend(self.token_fields(key, &token.access_token, &token.refresh_token)?);
+        Ok(fields)
+    }
+
+    /// Expiry of a session key: the lifetime of its refresh token, but not beyond the maximum
+    /// session age.
+    fn session_ttl(&self, refresh_expires_in: u64, created_at: Option<u64>) -> Option<i64> {
+        let remaining = self
+            .limits
+            .max_age
+            .zip(created_at)
+            .map(|(max_age, created_at)| {
+                let remaining = (created_at + max_age).saturating_sub(unix_time()).max(1);
+                i64::try_from(remaining).unwrap_or(i64::MAX)
+            });
+        match (ttl(refresh_expires_in), remaining) {
+            (Some(ttl), Some(remaining)) => Some(ttl.min(remaining)),
+            (ttl, remaining) => ttl.or(remaining),
+        }
     }
 
-    pub async fn set_token(&self, session_id: &str, token: &oidc::TokenResponse) -> Result<()> {
+    /// Delete a session that has exceeded its idle timeout or maximum age. Always fails with
+    /// [`Error::InvalidSession`], so that the user is sent to the login page.
+    async fn expire_session<T>(&self, key: &str) -> Result<T> {
+        info!("session has expired");
+        self.delete_session(key).await?;
+        Err(Error::invalid_session("session has expired"))
+    }
+
+    #[instrument(
+        name = "redis.ping",
+        skip_all,
+        fields(otel.kind = "client", db.system = "redis")
+    )]
+    pub async fn ping(&self) -> Result<()> {
+        let _timer = metrics::redis_timer("ping");
         let mut con = self.get_connection().await?;
-        redis::pipe()
-            .set_ex(
-                format!("access_token:{session_id}"),
-                &token.access_token,
-                token.expires_in,
-            )
-            .set_ex(
-                format!("refresh_token:{session_id}"),
-                &token.refresh_token,
-                token.refresh_expires_in,
-            )
-            .query_async(&mut con)
+        redis::cmd("PING").query_async::<_, ()>(&mut con).await?;
+        Ok(())
+    }
+
+    /// Store a new session. Its lifetime is bound to the lifetime of the refresh token.
+    #[instrument(
+        name = "redis.create_session",
+        skip_all,
+        fields(otel.kind = "client", db.system = "redis")
+    )]
+    pub async fn create_session(
+        &self,
+        key: &str,
+        token: &oidc::TokenResponse,
+        userinfo: &UserInfo,
+        client: &ClientInfo,
+    ) -> Result<()> {
+        let _timer = metrics::redis_timer("create_session");
+        let mut con = self.get_connection().await?;
+        let now = unix_time();
+        let mut fields = vec![
+            ("version".to_owned(), SESSION_VERSION.to_owned()),
+            ("created_at".to_owned(), now.to_string()),
+            ("claims".to_owned(), serde_json::to_string(userinfo)?),
+        ];
+        if let Some(ip) = &client.ip {
+            fields.push(("ip".to_owned(), ip.clone()));
+        }
+        if let Some(user_agent) = &client.user_agent {
+            fields.push(("user_agent".to_owned(), user_agent.clone()));
+        }
+        fields.extend(identity_fields(userinfo));
+        fields.extend(self.token_response_fields(key, token)?);
+        let mut pipe = redis::pipe();
+        pipe.atomic().del(key).hset_multiple(key, &fields).zadd(
+            REFRESH_INDEX,
+            key,
+            now + token.expires_in,
+        );
+        if let Some(ttl) = self.session_ttl(token.refresh_expires_in, Some(now)) {
+            pipe.expire(key, ttl);
+        }
+        pipe.query_async::<_, ()>(&mut con).await?;
+        index_session(&mut con, key, userinfo, token).await?;
+        Ok(())
+    }
+
+    /// Replace the tokens and claims of an existing session after a token refresh.
+    #[instrument(
+        name = "redis.update_session",
+        skip_all,
+        fields(otel.kind = "client", db.system = "redis")
+    )]
+    pub async fn update_session(
+        &self,
+        key: &str,
+        token: &oidc::TokenResponse,
+        userinfo: &UserInfo,
+    ) -> Result<()> {
+        let _timer = metrics::redis_timer("update_session");
+        let mut con = self.get_connection().await?;
+        let mut fields = vec![("claims".to_owned(), serde_json::to_string(userinfo)?)];
+        fields.extend(identity_fields(userinfo));
+        fields.extend(self.token_response_fields(key, token)?);
+        let updated: bool = Script::new(HSET_EXISTING)
+            .key(key)
+            .arg(fields)
+            .invoke_async(&mut con)
             .await?;
+        if updated {
+            let created_at: Option<u64> = if self.limits.max_age.is_some() {
+                con.hget(key, "created_at").await?
+            } else {
+                None
+            };
+            match self.session_ttl(token.refresh_expires_in, created_at) {
+                Some(ttl) => con.expire::<_, ()>(key, ttl).await?,
+                None => con.persist::<_, ()>(key).await?,
+            }
+            con.zadd::<_, _, _, ()>(REFRESH_INDEX, key, unix_time() + token.expires_in)
+                .await?;
+            index_session(&mut con, key, userinfo, token).await?;
+        }
         Ok(())
     }
 
-    pub async fn get_token(&self, session_id: &str) -> Result<Token> {
+    /// Follow the aliases left behind by session id rotations to the key of the current session.
+    #[instrument(
+        name = "redis.resolve_session_key",
+        skip_all,
+        fields(otel.kind = "client", db.system = "redis")
+    )]
+    pub async fn resolve_session_key(&self, key: &str) -> Result<String> {
+        let _timer = metrics::redis_timer("resolve_session_key");
         let mut con = self.get_connection().await?;
-        let (access_token, refresh_token) = con
-            .get(&[
-                format!("access_token:{session_id}"),
-                format!("refresh_token:{session_id}"),
-            ])
+        let mut key = key.to_owned();
+        for _ in 0..MAX_ALIASES {
+            match con.hget::<_, _, Option<String>>(&key, "rotated_to").await? {
+                Some(rotated_to) => key = rotated_to,
+                None => break,
+            }
+        }
+        Ok(key)
+    }
+
+    /// Fetch the tokens of a session.
+    ///
+    /// Returns `None` if the session does not exist, was stored in an unknown format or its
+    /// tokens cannot be decrypted. Expired sessions (see [`SessionLimits`]) are deleted.
+    #[instrument(
+        name = "redis.get_tokens",
+        skip_all,
+        fields(otel.kind = "client", db.system = "redis")
+    )]
+    pub async fn get_tokens(&self, key: &str) -> Result<Option<Tokens>> {
+        let _timer = metrics::redis_timer("get_tokens");
+        let mut con = self.get_connection().await?;
+        let mut fields: HashMap<String, String> = con.hgetall(key).await?;
+        // aliases of rotated sessions are resolved by `get_session_cache`
+        if fields.is_empty() || fields.contains_key("rotated_to") {
+            return Ok(None);
+        }
+
+        if fields.get("version").map(String::as_str) != Some(SESSION_VERSION) {
+            warn!(
+                "ignoring session with unsupported version: {:?}",
+                fields.get("version")
+            );
+            return Ok(None);
+        }
+        let created_at = fields.get("created_at").and_then(|x| x.parse().ok());
+        let last_used_at = fields.get("last_used_at").and_then(|x| x.parse().ok());
+        if self.limits.expired(created_at, last_used_at, unix_time()) {
+            return self.expire_session(key).await;
+        }
+        let token = fields
+            .remove("access_token")
+            .zip(fields.remove("refresh_token"))
+            .and_then(|(access, refresh)| {
+                Some(Tokens {
+                    access_token: self.decrypt(&format!("{key}:access_token"), access)?,
+                    refresh_token: self.decrypt(&format!("{key}:refresh_token"), refresh)?,
+                    expires_at: fields
+                        .get("access_token_expires_at")
+                        .and_then(|x| x.parse().ok()),
+                })
+            });
+        if token.is_none() {
+            debug!("no valid token found in redis");
+        }
+        Ok(token)
+    }
+
+    /// Move a session from the legacy `access_token:{id}`/`refresh_token:{id}` keys into a
+    /// session hash.
+    #[instrument(
+        name = "redis.migrate_legacy_session",
+        skip_all,
+        fields(otel.kind = "client", db.system = "redis")
+    )]
+    pub async fn migrate_legacy_session(&self, session_id: &str) -> Result<Option<Tokens>> {
+        let _timer = metrics::redis_timer("migrate_legacy_session");
+        let mut con = self.get_connection().await?;
+        let access_token_key = format!("access_token:{session_id}");
+        let refresh_token_key = format!("refresh_token:{session_id}");
+        let (access_token, refresh_token, refresh_ttl): (Option<String>, Option<String>, i64) =
+            redis::pipe()
+                .get(&access_token_key)
+                .get(&refresh_token_key)
+                .ttl(&refresh_token_key)
+                .query_async(&mut con)
+                .await?;
+        let Some(refresh_token) = refresh_token else {
+            return Ok(None);
+        };
+        // -1: the refresh token does not expire, -2: it has expired in the meantime
+        let refresh_expires_in = match refresh_ttl {
+            -2 => return Ok(None),
+            ttl => u64::try_from(ttl).unwrap_or(0),
+        };
+        let token = self
+            .decrypt(&refresh_token_key, refresh_token)
+            .map(|refresh_token| Tokens {
+                access_token: access_token
+                    .and_then(|x| self.decrypt(&access_token_key, x))
+                    .unwrap_or_default(),
+                refresh_token,
+                expires_at: None,
+            });
+        let Some(token) = token else {
+            debug!("no valid token found in redis");
+            return Ok(None);
+        };
+
+        info!("migrating legacy session");
+        let key = session_key(session_id);
+        let now = unix_time();
+        let mut fields = vec![
+            ("version".to_owned(), SESSION_VERSION.to_owned()),
+            ("created_at".to_owned(), now.to_string()),
+        ];
+        fields.extend(self.token_fields(&key, &token.access_token, &token.refresh_token)?);
+        let mut pipe = redis::pipe();
+        pipe.atomic().hset_multiple(&key, &fields);
+        if let Some(ttl) = self.session_ttl(refresh_expires_in, Some(now)) {
+            pipe.expire(&key, ttl);
+        }
+        pipe.del(&[access_token_key, refresh_token_key])
+            .query_async::<_, ()>(&mut con)
             .await?;
-        Ok(Token {
-            access_token,
-            refresh_token,
-        })
+
+        Ok(Some(token))
+    }
+
+    /// Acquire a lock on a session that is shared by all instances.
+    ///
+    /// The lock expires automatically after the configured lock ttl. If it cannot be acquired
+    /// within this time (e.g. because its holder has crashed), `None` is returned and the caller
+    /// should continue without holding the lock.
+    #[instrument(
+        name = "redis.lock_session",
+        skip_all,
+        fields(otel.kind = "client", db.system = "redis")
+    )]
+    pub async fn lock_session(&self, key: &str) -> Result<Option<SessionLock>> {
+        let _timer = metrics::redis_timer("lock_session");
+        let mut con = self.get_connection().await?;
+        let key = format!("lock:{key}");
+        let token: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(32)
+            .map(char::from)
+            .collect();
+        let ttl = u64::try_from(self.session_lock_ttl.as_millis()).unwrap_or(u64::MAX);
+        let deadline = tokio::time::Instant::now() + self.session_lock_ttl;
+        while tokio::time::Instant::now() < deadline {
+            let acquired: Option<String> = redis::cmd("SET")
+                .arg(&key)
+                .arg(&token)
+                .arg("NX")
+                .arg("PX")
+                .arg(ttl)
+                .query_async(&mut con)
+                .await?;
+            if acquired.is_some() {
+                return Ok(Some(SessionLock { key, token }));
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        warn!("could not acquire session lock");
+        Ok(None)
+    }
+
+    #[instrument(
+        name = "redis.unlock_session",
+        skip_all,
+        fields(otel.kind = "client", db.system = "redis")
+    )]
+    pub async fn unlock_session(&self, lock: SessionLock) -> Result<()> {
+        let _timer = metrics::redis_timer("unlock_session");
+        let mut con = self.get_connection().await?;
+        Script::new(UNLOCK)
+            .key(lock.key)
+            .arg(lock.token)
+            .invoke_async::<_, ()>(&mut con)
+            .await?;
+        Ok(())
+    }
+
+    /// Delete a session and notify all instances to drop their cached decisions.
+    ///
+    /// Returns whether the session existed.
+    #[instrument(
+        name = "redis.delete_session",
+        skip_all,
+        fields(otel.kind = "client", db.system = "redis")
+    )]
+    pub async fn delete_session(&self, key: &str) -> Result<bool> {
+        let _timer = metrics::redis_timer("delete_session");
+        let mut con = self.get_connection().await?;
+        if let Some(local_cache) = &self.local_cache {
+            local_cache.invalidate(key);
+        }
+        let sub: Option<String> = con.hget(key, "sub").await?;
+        let mut pipe = redis::pipe();
+        pipe.del(key)
+            .publish(INVALIDATION_CHANNEL, key)
+            .ignore()
+            .zrem(REFRESH_INDEX, key)
+            .ignore();
+        if let Some(sub) = sub {
+            pipe.srem(user_sessions_key(&sub), key).ignore();
+        }
+        let (deleted,): (u64,) = pipe.query_async(&mut con).await?;
+        Ok(deleted > 0)
+    }
+
+    /// Delete all sessions of a user and notify all instances to drop their cached decisions.
+    ///
+    /// Returns the number of deleted sessions.
+    #[instrument(
+        name = "redis.delete_user_sessions",
+        skip_all,
+        fields(otel.kind = "client", db.system = "redis")
+    )]
+    pub async fn delete_user_sessions(&self, sub: &str) -> Result<u64> {
+        let _timer = metrics::redis_timer("delete_user_sessions");
+        let mut con = self.get_connection().await?;
+        let index = user_sessions_key(sub);
+        let keys: Vec<String> = con.smembers(&index).await?;
+        if keys.is_empty() {
+            return Ok(0);
+        }
+        let mut pipe = redis::pipe();
+        pipe.del(&keys);
+        for key in &keys {
+            if let Some(local_cache) = &self.local_cache {
+                local_cache.invalidate(key);
+            }
+            pipe.publish(INVALIDATION_CHANNEL, key).ignore();
+        }
+        pipe.zrem(REFRESH_INDEX, &keys).ignore().del(index).ignore();
+        let (deleted,): (u64,) = pipe.query_async(&mut con).await?;
+        Ok(deleted)
+    }
+
+    /// List all sessions, or only the sessions of the user with the given `sub`.
+    #[instrument(
+        name = "redis.list_sessions",
+        skip_all,
+        fields(otel.kind = "client", db.system = "redis")
+    )]
+    pub async fn list_sessions(&self, sub: Option<&str>) -> Result<Vec<SessionInfo>> {
+        let _timer = metrics::redis_timer("list_sessions");
+        let mut con = self.get_connection().await?;
+        let keys: Vec<String> = match sub {
+            Some(sub) => con.smembers(user_sessions_key(sub)).await?,
+            None => session_keys(&mut con, "").await?,
+        };
+        let mut pipe = redis::pipe();
+        for key in &keys {
+            pipe.hget(
+                key,
+                &[
+                    "sub",
+                    "username",
+                    "created_at",
+                    "last_used_at",
+                    "ip",
+                    "user_agent",
+                ],
+            );
+        }
+        let values: Vec<SessionFields> = from_replies(pipe.query_async(&mut con).await?)?;
+        let mut sessions = Vec::with_capacity(keys.len());
+        let mut expired = Vec::new();
+        for (key, (session_sub, username, created_at, last_used_at, ip, user_agent)) in
+            keys.into_iter().zip(values)
+        {
+            // sessions that have expired since they have been found
+            let Some(created_at) = created_at else {
+                expired.push(key);
+                continue;
+            };
+            sessions.push(SessionInfo {
+                id: key.trim_start_matches("session:").to_owned(),
+                sub: session_sub,
+                username,
+                created_at,
+                last_used_at,
+                ip,
+                user_agent,
+            });
+        }
+        if let (Some(sub), false) = (sub, expired.is_empty()) {
+            con.srem::<_, _, ()>(user_sessions_key(sub), expired)
+                .await?;
+        }
+        Ok(sessions)
     }
 
+    /// Cache an authorization decision of a session. Storing a decision also marks the session
+    /// as recently used.
+    #[instrument(
+        name = "redis.update_session_cache",
+        skip_all,
+        fields(otel.kind = "client", db.system = "redis")
+    )]
     pub async fn update_session_cache(
         &self,
-        session_id: &str,
+        key: &str,
         role: &str,
         state: &SessionCache,
+        identity: &Arc<Identity>,
     ) -> Result<()> {
+        let _timer = metrics::redis_timer("update_session_cache");
         let mut con = self.get_connection().await?;
-        let key = format!("session:{session_id}:{role}");
-        match state {
-            SessionCache::Allowed => {
-                con.set_ex(key, "allowed", self.session_allowed_ttl).await?;
-            }
-            SessionCache::Forbidden => {
-                con.set_ex(key, "forbidden", self.session_forbidden_ttl)
-                    .await?;
-            }
+        let field = format!("decision:{role}");
+        let (allowed, decision_ttl) = match state {
+            SessionCache::Allowed => (true, self.session_allowed_ttl),
+            SessionCache::Forbidden => (false, self.session_forbidden_ttl),
             SessionCache::NotCached => {
-                con.del(key).await?;
+                if let Some(local_cache) = &self.local_cache {
+                    local_cache.remove(key, role);
+                }
+                con.hdel::<_, _, ()>(key, field).await?;
+                return Ok(());
             }
+            // stale entries are only ever produced by expiring allowed entries
+            SessionCache::Stale => return Ok(()),
+        };
+        // storing a decision marks the session as used, so only the maximum age can cut it short
+        let now = unix_time();
+        let created_at: Option<u64> = if self.limits.max_age.is_some() {
+            con.hget(key, "created_at").await?
+        } else {
+            None
+        };
+        let ttl = self
+            .limits
+            .remaining(created_at, Some(now), now)
+            .map_or(decision_ttl, |remaining| decision_ttl.min(remaining));
+        if let Some(local_cache) = &self.local_cache {
+            local_cache.insert(
+                key,
+                role,
+                (allowed, Arc::clone(identity)),
+                Duration::from_secs(ttl),
+            );
         }
+        let value = format!(
+            "{}:{}",
+            if allowed { "allowed" } else { "forbidden" },
+            now + ttl
+        );
+        Script::new(HSET_EXISTING)
+            .key(key)
+            .arg(field)
+            .arg(value)
+            .arg("last_used_at")
+            .arg(now)
+            .invoke_async::<_, ()>(&mut con)
+            .await?;
         Ok(())
     }
 
-    pub async fn get_session_cache(&self, session_id: &str, role: &str) -> Result<SessionCache> {
+    /// Fetch the cached authorization decision of a session together with the identity of its
+    /// user. If the id of the session has been rotated recently, the new session is used instead.
+    #[instrument(
+        name = "redis.get_session_cache",
+        skip_all,
+        fields(otel.kind = "client", db.system = "redis")
+    )]
+    pub async fn get_session_cache(&self, key: &str, role: &str) -> Result<SessionState> {
+        if let Some(local_cache) = &self.local_cache {
+            if let Some((allowed, identity)) = local_cache.get(key, role) {
+                metrics::session_cache(true);
+                let cache = if allowed {
+                    SessionCache::Allowed
+                } else {
+                    SessionCache::Forbidden
+                };
+                return Ok(SessionState {
+                    cache,
+                    identity,
+                    key: key.to_owned(),
+                    rotate: false,
+                });
+            }
+        }
+
+        let _timer = metrics::redis_timer("get_session_cache");
         let mut con = self.get_connection().await?;
-        let value: Option<String> = con.get(format!("session:{session_id}:{role}")).await?;
+        let (key, values) = cached_fields(&mut con, key, role).await?;
+        let (
+            value,
+            sub,
+            username,
+            created_at,
+            last_used_at,
+            ip,
+            user_agent,
+            id_issued_at,
+            refreshed_at,
+            _,
+        ) = values;
+        let now = unix_time();
+        if self.limits.expired(created_at, last_used_at, now) {
+            return self.expire_session(&key).await;
+        }
+        let identity = Arc::new(Identity {
+            sub,
+            username,
+            client: ClientInfo { ip, user_agent },
+        });
+        let rotate = self
+            .rotation
+            .due(id_issued_at.or(created_at), refreshed_at, now);
+        let Some(value) = value else {
+            metrics::session_cache(false);
+            return Ok(SessionState {
+                cache: SessionCache::NotCached,
+                identity,
+                key,
+                rotate,
+            });
+        };
 
-        Ok(match value {
-            Some(ref s) if s == "allowed" => SessionCache::Allowed,
-            Some(ref s) if s == "forbidden" => SessionCache::Forbidden,
-            None => SessionCache::NotCached,
-            Some(ref s) => {
-                warn!("invalid session cache value for {session_id}: {s}");
-                SessionCache::NotCached
+        let cache = parse_session_cache(&value, now, self.session_grace_period);
+        metrics::session_cache(matches!(
+            cache,
+            SessionCache::Allowed | SessionCache::Forbidden
+        ));
+        // decisions are only stored again once they have expired, so cache hits have to record
+        // the use of the session themselves
+        let last_used_at = if !matches!(cache, SessionCache::NotCached)
+            && last_used_at.is_none_or(|last_used_at| last_used_at + LAST_USED_INTERVAL <= now)
+        {
+            touch_session(&mut con, &key, now).await;
+            Some(now)
+        } else {
+            last_used_at
+        };
+        if let (Some(local_cache), Some((_, expires_at))) =
+            (&self.local_cache, parse_decision(&value))
+        {
+            let remaining = self.limits.remaining(created_at, last_used_at, now);
+            let ttl = Duration::from_secs(
+                expires_at
+                    .saturating_sub(now)
+                    .min(remaining.unwrap_or(u64::MAX)),
+            );
+            match cache {
+                SessionCache::Allowed => {
+                    local_cache.insert(&key, role, (true, Arc::clone(&identity)), ttl);
+                }
+                SessionCache::Forbidden => {
+                    local_cache.insert(&key, role, (false, Arc::clone(&identity)), ttl);
+                }
+                SessionCache::NotCached | SessionCache::Stale => {}
             }
+        }
+        Ok(SessionState {
+            cache,
+            identity,
+            key,
+            rotate,
         })
     }
+
+    /// Move a session to a new key. The old key stays valid as an alias for the new key during
+    /// the rotation grace period.
+    ///
+    /// Returns `false` if the session does not exist (anymore), e.g. because it has already been
+    /// rotated by a concurrent request.
+    #[instrument(
+        name = "redis.rotate_session",
+        skip_all,
+        fields(otel.kind = "client", db.system = "redis")
+    )]
+    pub async fn rotate_session(&self, key: &str, new_key: &str) -> Result<bool> {
+        // tokens are encrypted with the key as associated data, so they have to be re-encrypted
+        let Some(tokens) = self.get_tokens(key).await? else {
+            return Ok(false);
+        };
+        let _timer = metrics::redis_timer("rotate_session");
+        let mut con = self.get_connection().await?;
+        let mut fields = vec![("id_issued_at".to_owned(), unix_time().to_string())];
+        fields.extend(self.token_fields(new_key, &tokens.access_token, &tokens.refresh_token)?);
+        let rotated: bool = Script::new(ROTATE_SESSION)
+            .key(key)
+            .key(new_key)
+            .arg(self.rotation.grace_period.max(1))
+            .arg(fields)
+            .invoke_async(&mut con)
+            .await?;
+        if !rotated {
+            return Ok(false);
+        }
+        if let Some(local_cache) = &self.local_cache {
+            local_cache.invalidate(key);
+        }
+        let (sub, expires_at): (Option<String>, Option<u64>) = con
+            .hget(new_key, &["sub", "access_token_expires_at"])
+            .await?;
+        let mut pipe = redis::pipe();
+        pipe.publish(INVALIDATION_CHANNEL, key)
+            .ignore()
+            .zrem(REFRESH_INDEX, key)
+            .ignore();
+        if let Some(expires_at) = expires_at {
+            pipe.zadd(REFRESH_INDEX, new_key, expires_at).ignore();
+        }
+        if let Some(sub) = sub {
+            pipe.srem(user_sessions_key(&sub), key)
+                .ignore()
+                .sadd(user_sessions_key(&sub), new_key)
+                .ignore();
+        }
+        pipe.query_async::<_, ()>(&mut con).await?;
+        Ok(true)
+    }
+
+    /// Find sessions whose access token expires before `before` and that have been used since
+    /// their tokens were last refreshed.
+    ///
+    /// Sessions that no longer exist are removed from the index, as are unused sessions whose
+    /// access token has expired already. They are refreshed on their next use instead.
+    #[instrument(
+        name = "redis.expiring_sessions",
+        skip_all,
+        fields(otel.kind = "client", db.system = "redis")
+    )]
+    pub async fn expiring_sessions(&self, before: u64) -> Result<Vec<String>> {
+        let _timer = metrics::redis_timer("expiring_sessions");
+        let mut con = self.get_connection().await?;
+        let keys: Vec<String> = con.zrangebyscore(REFRESH_INDEX, "-inf", before).await?;
+        if keys.is_empty() {
+            return Ok(keys);
+        }
+        let mut pipe = redis::pipe();
+        for key in &keys {
+            pipe.hget(
+                key,
+                &["access_token_expires_at", "refreshed_at", "last_used_at"],
+            );
+        }
+        let values: Vec<(Option<u64>, Option<u64>, Option<u64>)> =
+            from_replies(pipe.query_async(&mut con).await?)?;
+        let now = unix_time();
+        let mut expiring = Vec::new();
+        let mut stale = Vec::new();
+        for (key, (expires_at, refreshed_at, last_used_at)) in keys.into_iter().zip(values) {
+            let used = last_used_at.unwrap_or_default() >= refreshed_at.unwrap_or_default();
+            match expires_at {
+                Some(expires_at) if expires_at <= before && used => expiring.push(key),
+                Some(expires_at) if expires_at > now => {}
+                _ => stale.push(key),
+            }
+        }
+        if !stale.is_empty() {
+            con.zrem::<_, _, ()>(REFRESH_INDEX, stale).await?;
+        }
+        Ok(expiring)
+    }
+
+    /// Find the keys of all sessions whose id (as shown by [`Redis::list_sessions`]) starts with
+    /// the given prefix.
+    #[instrument(
+        name = "redis.find_sessions",
+        skip_all,
+        fields(otel.kind = "client", db.system = "redis")
+    )]
+    pub async fn find_sessions(&self, id_prefix: &str) -> Result<Vec<String>> {
+        let _timer = metrics::redis_timer("find_sessions");
+        let mut con = self.get_connection().await?;
+        session_keys(&mut con, id_prefix).await
+    }
+
+    /// Fetch all fields of a session except for its tokens.
+    #[instrument(
+        name = "redis.session_details",
+        skip_all,
+        fields(otel.kind = "client", db.system = "redis")
+    )]
+    pub async fn session_details(&self, key: &str) -> Result<Option<SessionDetails>> {
+        let _timer = metrics::redis_timer("session_details");
+        let mut con = self.get_connection().await?;
+        let (fields, ttl): (HashMap<String, String>, i64) = redis::pipe()
+            .hgetall(key)
+            .ttl(key)
+            .query_async(&mut con)
+            .await?;
+        if fields.is_empty() {
+            return Ok(None);
+        }
+        let mut fields: Vec<_> = fields
+            .into_iter()
+            .filter(|(field, _)| !matches!(field.as_str(), "access_token" | "refresh_token"))
+            .collect();
+        fields.sort();
+        Ok(Some(SessionDetails {
+            fields,
+            ttl: u64::try_from(ttl).ok(),
+        }))
+    }
+
+    /// Delete all sessions and user indexes and notify all instances to drop their cached
+    /// decisions.
+    ///
+    /// Returns the number of deleted sessions.
+    #[instrument(
+        name = "redis.delete_all_sessions",
+        skip_all,
+        fields(otel.kind = "client", db.system = "redis")
+    )]
+    pub async fn delete_all_sessions(&self) -> Result<u64> {
+        let _timer = metrics::redis_timer("delete_all_sessions");
+        let mut con = self.get_connection().await?;
+        if let Some(local_cache) = &self.local_cache {
+            local_cache.clear();
+        }
+        let indexes: Vec<String> = con
+            .scan_match::<_, String>("user:*:sessions")
+            .await?
+            .collect()
+            .await;
+        let mut deleted = 0;
+        for keys in session_keys(&mut con, "").await?.chunks(1000) {
+            let mut pipe = redis::pipe();
+            pipe.del(keys);
+            for key in keys {
+                pipe.publish(INVALIDATION_CHANNEL, key).ignore();
+            }
+            let (count,): (u64,) = pipe.query_async(&mut con).await?;
+            deleted += count;
+        }
+        for chunk in indexes.chunks(1000) {
+            con.del::<_, ()>(chunk).await?;
+        }
+        con.del::<_, ()>(REFRESH_INDEX).await?;
+        Ok(deleted)
+    }
+
+    /// Fetch the raw contents of all sessions, e.g. to move them to another redis instance.
+    #[instrument(
+        name = "redis.export_sessions",
+        skip_all,
+        fields(otel.kind = "client", db.system = "redis")
+    )]
+    pub async fn export_sessions(&self) -> Result<Vec<StoredSession>> {
+        let _timer = metrics::redis_timer("export_sessions");
+        let mut con = self.get_connection().await?;
+        let keys = session_keys(&mut con, "").await?;
+        let mut pipe = redis::pipe();
+        for key in &keys {
+            pipe.hgetall(key).ttl(key);
+        }
+        let values: Vec<(HashMap<String, String>, i64)> = pipe.query_async(&mut con).await?;
+        Ok(keys
+            .into_iter()
+            .zip(values)
+            // sessions that have expired since they have been found
+            .filter(|(_, (fields, _))| !fields.is_empty())
+            .map(|(key, (fields, ttl))| StoredSession {
+                key,
+                ttl: u64::try_from(ttl).ok(),
+                fields,
+            })
+            .collect())
+    }
+
+    /// Store sessions exported by [`Redis::export_sessions`], replacing existing sessions with
+    /// the same keys.
+    ///
+    /// Tokens are stored as they are, so the session encryption keys have to stay the same. Nothing
+    /// is imported if any of the keys is not a session key, as that would overwrite arbitrary
+    /// keys.
+    #[instrument(
+        name = "redis.import_sessions",
+        skip_all,
+        fields(otel.kind = "client", db.system = "redis")
+    )]
+    pub async fn import_sessions(&self, sessions: &[StoredSession]) -> Result<()> {
+        let _timer = metrics::redis_timer("import_sessions");
+        if let Some(session) = sessions.iter().find(|s| !is_session_key(&s.key)) {
+            return Err(Error::Internal(eyre::eyre!(
+                "not a session key: {:?}",
+                session.key
+            )));
+        }
+        let mut con = self.get_connection().await?;
+        for session in sessions {
+            let mut pipe = redis::pipe();
+            pipe.atomic()
+                .del(&session.key)
+                .hset_multiple(&session.key, &session.fields.iter().collect::<Vec<_>>());
+            if let Some(ttl) = session.ttl {
+                pipe.expire(&session.key, i64::try_from(ttl).unwrap_or(i64::MAX));
+            }
+            let expires_at = session.fields.get("access_token_expires_at");
+            if let Some(expires_at) = expires_at.and_then(|x| x.parse::<u64>().ok()) {
+                pipe.zadd(REFRESH_INDEX, &session.key, expires_at);
+            }
+            pipe.query_async::<_, ()>(&mut con).await?;
+            if let Some(sub) = session.fields.get("sub") {
+                Script::new(INDEX_SESSION)
+                    .key(user_sessions_key(sub))
+                    .arg(&session.key)
+                    .arg(session.ttl.unwrap_or(0))
+                    .invoke_async::<_, ()>(&mut con)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
 }
 
-#[derive(Debug)]
-pub struct Token {
+/// Convert the replies of a pipeline whose commands each return multiple values. (Parsing a
+/// `Vec` of tuples directly would treat the replies as one flat list.)
+fn from_replies<T: FromRedisValue>(replies: Vec<Value>) -> RedisResult<Vec<T>> {
+    replies.into_iter().map(from_owned_redis_value).collect()
+}
+
+/// Fetch the fields needed for authorization requests from a session hash, following the
+/// aliases left behind by session id rotations. Returns the key of the session that was found.
+async fn cached_fields(
+    con: &mut MultiplexedConnection,
+    key: &str,
+    role: &str,
+) -> Result<(String, CachedFields)> {
+    let fields = [
+        format!("decision:{role}"),
+        "sub".into(),
+        "username".into(),
+        "created_at".into(),
+        "last_used_at".into(),
+        "ip".into(),
+        "user_agent".into(),
+        "id_issued_at".into(),
+        "refreshed_at".into(),
+        "rotated_to".into(),
+    ];
+    let mut key = key.to_owned();
+    for _ in 0..MAX_ALIASES {
+        let values: CachedFields = con.hget(&key, &fields).await?;
+        match values.9 {
+            Some(rotated_to) => key = rotated_to,
+            None => return Ok((key, values)),
+        }
+    }
+    Ok((key, CachedFields::default()))
+}
+
+/// Record the use of a session. Failures are only logged, as they don't affect the request.
+async fn touch_session(con: &mut MultiplexedConnection, key: &str, now: u64) {
+    let _timer = metrics::redis_timer("touch_session");
+    let result = Script::new(HSET_EXISTING)
+        .key(key)
+        .arg("last_used_at")
+        .arg(now)
+        .invoke_async::<_, ()>(con)
+        .await;
+    if let Err(err) = result {
+        warn!("could not record use of session: {err}");
+    }
+}
+
+/// Keys of all sessions whose key hash starts with the given prefix.
+async fn session_keys(con: &mut MultiplexedConnection, prefix: &str) -> Result<Vec<String>> {
+    Ok(con
+        .scan_match::<_, String>(format!("session:{prefix}*"))
+        .await?
+        .filter(|key| std::future::ready(is_session_key(key)))
+        .collect()
+        .await)
+}
+
+/// Add a session to the index of its user, so that all sessions of a user can be found.
+async fn index_session(
+    con: &mut MultiplexedConnection,
+    key: &str,
+    userinfo: &UserInfo,
+    token: &oidc::TokenResponse,
+) -> Result<()> {
+    let Some(sub) = &userinfo.sub else {
+        return Ok(());
+    };
+    Script::new(INDEX_SESSION)
+        .key(user_sessions_key(sub))
+        .arg(key)
+        .arg(ttl(token.refresh_expires_in).unwrap_or(0))
+        .invoke_async::<_, ()>(con)
+        .await?;
+    Ok(())
+}
+
+fn identity_fields(userinfo: &UserInfo) -> Vec<(String, String)> {
+    [
+        ("sub", &userinfo.sub),
+        ("username", &userinfo.preferred_username),
+    ]
+    .into_iter()
+    .filter_map(|(field, value)| Some((field.to_owned(), value.clone()?)))
+    .collect()
+}
+
+fn parse_decision(value: &str) -> Option<(&str, u64)> {
+    value
+        .split_once(':')
+        .and_then(|(decision, expires_at)| Some((decision, expires_at.parse().ok()?)))
+}
+
+fn parse_session_cache(value: &str, now: u64, grace_period: Option<u64>) -> SessionCache {
+    match parse_decision(value) {
+        Some(("allowed", expires_at)) if expires_at > now => SessionCache::Allowed,
+        Some(("allowed", expires_at))
+            if grace_period.is_some_and(|grace_period| expires_at + grace_period > now) =>
+        {
+            SessionCache::Stale
+        }
+        Some(("forbidden", expires_at)) if expires_at > now => SessionCache::Forbidden,
+        Some(("allowed" | "forbidden", _)) => SessionCache::NotCached,
+        _ => {
+            warn!("invalid session cache value: {value}");
+            SessionCache::NotCached
+        }
+    }
+}
+
+/// Redis key of a session. Session ids are hashed, so that the keys themselves cannot be used
+/// as session cookies.
+pub fn session_key(session_id: &str) -> String {
+    format!("session:{:x}", Sha256::digest(session_id))
+}
+
+/// Redis key of the set of session keys of a user.
+fn user_sessions_key(sub: &str) -> String {
+    format!("user:{sub}:sessions")
+}
+
+/// Check whether a key matching `session:*` is a session hash (and not e.g. a legacy key).
+pub fn is_session_key(key: &str) -> bool {
+    key.strip_prefix("session:")
+        .is_some_and(|hash| hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Convert a token lifetime into a key expiry. Keycloak reports `0` for tokens that do not
+/// expire (e.g. offline tokens).
+fn ttl(expires_in: u64) -> Option<i64> {
+    match i64::try_from(expires_in) {
+        Ok(0) | Err(_) => None,
+        Ok(ttl) => Some(ttl),
+    }
+}
+
+pub struct Tokens {
     pub access_token: String,
     pub refresh_token: String,
+    /// Unix timestamp at which the access token expires, if known.
+    pub expires_at: Option<u64>,
+}
+
+/// The user a session belongs to.
+#[derive(Debug, Default)]
+#[cfg_attr(test, derive(PartialEq, Eq))]
+pub struct Identity {
+    pub sub: Option<String>,
+    pub username: Option<String>,
+    /// The client that logged in, which the session may be bound to.
+    pub client: ClientInfo,
+}
+
+/// A stored session as shown to administrators.
+#[derive(Debug, Serialize)]
+pub struct SessionInfo {
+    /// Hash of the session id, as used in its redis key.
+    pub id: String,
+    pub sub: Option<String>,
+    pub username: Option<String>,
+    pub created_at: u64,
+    /// Last time an authorization decision has been stored for the session.
+    pub last_used_at: Option<u64>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+pub struct SessionDetails {
+    /// All fields of the session hash except for the tokens, sorted by name.
+    pub fields: Vec<(String, String)>,
+    /// Remaining lifetime in seconds, `None` if the session does not expire.
+    pub ttl: Option<u64>,
+}
+
+/// Raw contents of a session hash, as exported and imported by the `sessions` subcommands.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StoredSession {
+    pub key: String,
+    /// Remaining lifetime in seconds, `None` if the session does not expire.
+    pub ttl: Option<u64>,
+    pub fields: HashMap<String, String>,
+}
+
+/// Limits on the lifetime of sessions, on top of the lifetime of their refresh tokens.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SessionLimits {
+    /// Seconds without authorization requests after which a session expires.
+    pub idle_timeout: Option<u64>,
+    /// Seconds after login after which a session expires, even if its tokens are refreshed.
+    pub max_age: Option<u64>,
+}
+
+impl SessionLimits {
+    fn expired(&self, created_at: Option<u64>, last_used_at: Option<u64>, now: u64) -> bool {
+        self.remaining(created_at, last_used_at, now) == Some(0)
+    }
+
+    /// Seconds until a session exceeds one of the limits, `None` if no limit applies.
+    fn remaining(
+        &self,
+        created_at: Option<u64>,
+        last_used_at: Option<u64>,
+        now: u64,
+    ) -> Option<u64> {
+        let max_age = self
+            .max_age
+            .zip(created_at)
+            .map(|(max_age, created_at)| created_at + max_age);
+        // sessions that have never been used count as used at login
+        let idle = self
+            .idle_timeout
+            .zip(last_used_at.or(created_at))
+            .map(|(idle_timeout, last_used_at)| last_used_at + idle_timeout);
+        max_age
+            .into_iter()
+            .chain(idle)
+            .min()
+            .map(|deadline| deadline.saturating_sub(now))
+    }
+}
+
+type CachedFields = (
+    Option<String>,
+    Option<String>,
+    Option<String>,
+    Option<u64>,
+    Option<u64>,
+    Option<String>,
+    Option<String>,
+    Option<u64>,
+    Option<u64>,
+    Option<String>,
+);
+
+/// When to replace the id of a session with a new one.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SessionRotation {
+    /// Seconds after which a session gets a new id.
+    pub interval: Option<u64>,
+    /// Whether a session gets a new id after its tokens have been refreshed.
+    pub on_refresh: bool,
+    /// Seconds the previous id stays valid after a rotation.
+    pub grace_period: u64,
+}
+
+impl SessionRotation {
+    fn due(&self, issued_at: Option<u64>, refreshed_at: Option<u64>, now: u64) -> bool {
+        let Some(issued_at) = issued_at else {
+            return false;
+        };
+        self.interval
+            .is_some_and(|interval| issued_at + interval <= now)
+            || (self.on_refresh
+                && refreshed_at.is_some_and(|refreshed_at| refreshed_at > issued_at))
+    }
+}
+
+/// The cached authorization decision of a session, see [`Redis::get_session_cache`].
+pub struct SessionState {
+    pub cache: SessionCache,
+    pub identity: Arc<Identity>,
+    /// Key of the session, which differs from the requested key if the session id has been
+    /// rotated.
+    pub key: String,
+    /// Whether the session id is due to be rotated.
+    pub rotate: bool,
+}
+
+type SessionFields = (
+    Option<String>,
+    Option<String>,
+    Option<u64>,
+    Option<u64>,
+    Option<String>,
+    Option<String>,
+);
+
+/// In-memory cache of authorization decisions and the identity of the session's user.
+pub type DecisionCache = LocalCache<(bool, Arc<Identity>)>;
+
+pub struct SessionLock {
+    key: String,
+    token: String,
 }
 
 #[derive(Debug)]
+#[cfg_attr(test, derive(PartialEq, Eq))]
 pub enum SessionCache {
     Allowed,
     Forbidden,
     NotCached,
+    /// An expired [`SessionCache::Allowed`] entry that may still be used while the identity
+    /// provider is unavailable.
+    Stale,
 }
 
 #[cfg(test)]
@@ -118,12 +1356,30 @@ mod tests {
 
     #[test]
     fn test_new_err() {
-        assert!(Redis::new("asdiofjasfdjoi", 1337, 42).is_err());
+        assert!(Redis::new(
+            "asdiofjasfdjoi",
+            None,
+            1337,
+            42,
+            None,
+            None,
+            Duration::from_secs(21)
+        )
+        .is_err());
     }
 
     #[test]
     fn test_new_ok() {
-        let res = Redis::new("redis://my_redis_host:6379/42", 1337, 42).unwrap();
+        let res = Redis::new(
+            "redis://my_redis_host:6379/42",
+            None,
+            1337,
+            42,
+            Some(300),
+            None,
+            Duration::from_secs(21),
+        )
+        .unwrap();
         let connection_info = res.client.get_connection_info();
         assert_eq!(
             connection_info.addr,
@@ -134,5 +1390,188 @@ mod tests {
         assert_eq!(connection_info.redis.password, None);
         assert_eq!(res.session_allowed_ttl, 1337);
         assert_eq!(res.session_forbidden_ttl, 42);
+        assert_eq!(res.session_grace_period, Some(300));
+        assert_eq!(res.session_lock_ttl, Duration::from_secs(21));
+    }
+
+    #[test]
+    fn test_is_session_key() {
+        assert!(is_session_key(&session_key("my_session")));
+        assert!(!is_session_key("session:my_session:my_role"));
+        assert!(!is_session_key("lock:session:my_session"));
+    }
+
+    #[tokio::test]
+    async fn test_import_sessions_rejects_other_keys() {
+        // fails before connecting to the (unreachable) server
+        let redis = Redis::new(
+            "redis://127.0.0.1:1",
+            None,
+            60,
+            10,
+            None,
+            None,
+            Duration::from_secs(5),
+        )
+        .unwrap();
+        let session = |key: &str| StoredSession {
+            key: key.to_owned(),
+            ttl: None,
+            fields: HashMap::new(),
+        };
+        let sessions = [session(&session_key("my_session")), session("lock:my_lock")];
+        let err = redis.import_sessions(&sessions).await.unwrap_err();
+        assert!(matches!(err, Error::Internal(_)));
+        assert!(err.to_string().contains("lock:my_lock"));
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a redis server at REDIS_URL"]
+    async fn test_migrate_legacy_session() {
+        let redis_url =
+            std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://localhost".to_owned());
+        let key = session_key("my_legacy_session");
+        for (max_age, expected) in [(None, -1..=-1), (Some(600), 1..=600)] {
+            let redis = Redis::new(&redis_url, None, 60, 10, None, None, Duration::from_secs(5))
+                .unwrap()
+                .with_limits(SessionLimits {
+                    idle_timeout: None,
+                    max_age,
+                });
+            let mut con = redis.get_connection().await.unwrap();
+            // refresh tokens without an expiry stay persistent, unless there is a maximum age
+            con.set::<_, _, ()>("refresh_token:my_legacy_session", "refresh")
+                .await
+                .unwrap();
+            let token = redis.migrate_legacy_session("my_legacy_session").await;
+            assert_eq!(token.unwrap().unwrap().refresh_token, "refresh");
+            let ttl: i64 = con.ttl(&key).await.unwrap();
+            assert!(expected.contains(&ttl), "unexpected ttl {ttl}");
+            let exists: bool = con.exists("refresh_token:my_legacy_session").await.unwrap();
+            assert!(!exists);
+            redis.delete_session(&key).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a redis server at REDIS_URL"]
+    async fn test_expiring_sessions() {
+        let redis_url =
+            std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://localhost".to_owned());
+        let redis =
+            Redis::new(&redis_url, None, 60, 10, None, None, Duration::from_secs(5)).unwrap();
+        let mut con = redis.get_connection().await.unwrap();
+        let create = |session_id, expires_in| {
+            let key = session_key(session_id);
+            let token = oidc::TokenResponse {
+                access_token: "access".to_owned(),
+                refresh_token: "refresh".to_owned(),
+                expires_in,
+                refresh_expires_in: 1800,
+            };
+            let userinfo = UserInfo {
+                sub: None,
+                preferred_username: None,
+                roles: vec![],
+                groups: vec![],
+            };
+            let redis = &redis;
+            async move {
+                redis
+                    .create_session(&key, &token, &userinfo, &ClientInfo::default())
+                    .await
+                    .unwrap();
+                key
+            }
+        };
+        let used = create("used", 0).await;
+        let unused = create("unused", 0).await;
+        let valid = create("valid", 3600).await;
+        con.hset::<_, _, _, ()>(&used, "last_used_at", unix_time() + 1)
+            .await
+            .unwrap();
+
+        // other tests may store sessions in the same redis concurrently
+        let expiring = redis.expiring_sessions(unix_time() + 30).await.unwrap();
+        assert!(expiring.contains(&used));
+        assert!(!expiring.contains(&unused) && !expiring.contains(&valid));
+        // the unused session is refreshed on its next use instead
+        let indexed = |key| {
+            let mut con = con.clone();
+            async move {
+                let score: Option<u64> = con.zscore(REFRESH_INDEX, key).await.unwrap();
+                score.is_some()
+            }
+        };
+        assert!(indexed(&used).await);
+        assert!(!indexed(&unused).await);
+        assert!(indexed(&valid).await);
+
+        for key in [&used, &unused, &valid] {
+            redis.delete_session(key).await.unwrap();
+            assert!(!indexed(key).await);
+        }
+    }
+
+    #[test]
+    fn test_parse_session_cache() {
+        let parse = |value, grace_period| parse_session_cache(value, 1000, grace_period);
+        assert_eq!(parse("allowed:1001", None), SessionCache::Allowed);
+        assert_eq!(parse("allowed:1000", None), SessionCache::NotCached);
+        assert_eq!(parse("allowed:1000", Some(60)), SessionCache::Stale);
+        assert_eq!(parse("allowed:940", Some(60)), SessionCache::NotCached);
+        assert_eq!(parse("forbidden:1001", Some(60)), SessionCache::Forbidden);
+        assert_eq!(parse("forbidden:1000", Some(60)), SessionCache::NotCached);
+        assert_eq!(parse("allowed", None), SessionCache::NotCached);
+        assert_eq!(parse("unknown:1001", None), SessionCache::NotCached);
+    }
+
+    #[test]
+    fn test_session_limits() {
+        let limits = SessionLimits {
+            idle_timeout: Some(100),
+            max_age: Some(1000),
+        };
+        assert_eq!(limits.remaining(Some(0), None, 50), Some(50));
+        assert_eq!(limits.remaining(Some(0), Some(900), 950), Some(50));
+        assert_eq!(limits.remaining(Some(0), Some(950), 960), Some(40));
+        assert!(!limits.expired(Some(0), Some(900), 999));
+        assert!(limits.expired(Some(0), Some(900), 1000));
+        assert!(limits.expired(Some(0), Some(100), 200));
+        // sessions that don't exist
+        assert!(!limits.expired(None, None, 1000));
+
+        assert_eq!(
+            SessionLimits::default().remaining(Some(0), None, 5000),
+            None
+        );
+    }
+
+    #[test]
+    fn test_session_rotation() {
+        let by_interval = SessionRotation {
+            interval: Some(100),
+            on_refresh: false,
+            grace_period: 10,
+        };
+        assert!(!by_interval.due(Some(1000), Some(1050), 1099));
+        assert!(by_interval.due(Some(1000), Some(1000), 1100));
+        assert!(!by_interval.due(None, None, 5000));
+
+        let on_refresh = SessionRotation {
+            on_refresh: true,
+            ..Default::default()
+        };
+        assert!(!on_refresh.due(Some(1000), Some(1000), 5000));
+        assert!(on_refresh.due(Some(1000), Some(1001), 1001));
+        assert!(!on_refresh.due(Some(1000), None, 5000));
+    }
+
+    #[test]
+    fn test_session_key() {
+        assert_eq!(
+            session_key("my_session"),
+            "session:6a0131975a5a0b545b3cd53e7a774f04f5f4be4d60d0e89d60f4e1750f683889"
+        );
     }
 }
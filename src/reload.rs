@@ -0,0 +1,271 @@
+use std::{
+    env, fs,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use eyre::{bail, ensure, Result};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+};
+use tracing::{error, info, warn};
+
+use crate::{
+    client::ClientPolicy,
+    config::{self, ClientSecret, Config},
+    crypto::Cipher,
+    http,
+    oidc::OIDC,
+    policy::Policy,
+    redis::{DecisionCache, Redis, SessionLimits, SessionRotation, LAST_USED_INTERVAL},
+};
+
+/// The current OIDC client, which is replaced whenever the configuration is reloaded. Requests
+/// that are already in flight keep using the client they started with.
+#[derive(Clone)]
+pub struct SharedOidc(watch::Receiver<Arc<OIDC>>);
+
+impl SharedOidc {
+    pub fn current(&self) -> Arc<OIDC> {
+        Arc::clone(&self.0.borrow())
+    }
+}
+
+/// Reloads the configuration and secrets on SIGHUP and, if `reload_interval` is set,
+/// whenever one of the files they are read from has been modified.
+pub struct Reloader {
+    sender: watch::Sender<Arc<OIDC>>,
+    local_cache: Option<Arc<DecisionCache>>,
+}
+
+impl Reloader {
+    pub fn new(config: &Config, local_cache: Option<Arc<DecisionCache>>) -> Result<Self> {
+        let oidc = build_oidc(config, local_cache.clone())?;
+        let (sender, _) = watch::channel(Arc::new(oidc));
+        Ok(Self {
+            sender,
+            local_cache,
+        })
+    }
+
+    pub fn oidc(&self) -> SharedOidc {
+        SharedOidc(self.sender.subscribe())
+    }
+
+    pub fn spawn(self, config: &Config) -> Result<()> {
+        let mut sighup = signal(SignalKind::hangup())?;
+        let interval = config.reload_interval.map(Duration::from_secs);
+        let mut files = WatchedFiles::new(watched_paths(config));
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = sighup.recv() => info!("received SIGHUP, reloading config"),
+                    () = files.changed(interval) => info!("config files changed, reloading config"),
+                }
+                match self.reload() {
+                    Ok(new_config) => {
+                        files = WatchedFiles::new(watched_paths(&new_config));
+                        info!("config reloaded");
+                    }
+                    // the previous client stays in place
+                    Err(err) => error!("could not reload config: {err:#}"),
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn reload(&self) -> Result<Config> {
+        let config = config::load()?;
+        let oidc = build_oidc(&config, self.local_cache.clone())?;
+        self.sender.send_replace(Arc::new(oidc));
+        Ok(config)
+    }
+}
+
+/// Create the OIDC client (and everything it depends on) from the given configuration.
+pub fn build_oidc(config: &Config, local_cache: Option<Arc<DecisionCache>>) -> Result<OIDC> {
+    if let Some(idle_timeout) = config.session_idle_timeout {
+        // uses of a session are recorded with a delay, during which it must not expire
+        let delay = LAST_USED_INTERVAL + local_cache.as_ref().map_or(0, |_| config.local_cache_ttl);
+        ensure!(
+            idle_timeout > delay,
+            "SESSION_IDLE_TIMEOUT must be longer than {delay} seconds"
+        );
+    }
+    let redis = Redis::new(
+        config.redis_url.expose(),
+        load_cipher(config)?,
+        config.session_allowed_ttl,
+        config.session_forbidden_ttl,
+        config.session_grace_period,
+        local_cache,
+        // long enough for a token refresh and a userinfo request
+        Duration::from_secs(config.http_timeout * 2 + 1),
+    )?
+    .with_limits(SessionLimits {
+        idle_timeout: config.session_idle_timeout,
+        max_age: config.session_max_age,
+    })
+    .with_rotation(SessionRotation {
+        interval: config.session_rotation_interval,
+        on_refresh: config.session_rotate_on_refresh,
+        grace_period: config.session_rotation_grace_period,
+    });
+    Ok(OIDC::new(
+        http::client(config)?,
+        &config.keycloak_base_url,
+        config.client_id.clone(),
+        load_client_secret(config)?,
+        config.auth_callback_path.clone(),
+        redis,
+        config.token_refresh_window,
+    )?
+    .with_logout_path(config.auth_logout_path.clone())
+    .with_client_policy(ClientPolicy::new(config)?)
+    .with_policy(
+        config
+            .policy_file
+            .as_deref()
+            .map(Policy::load)
+            .transpose()?,
+    )
+    .with_cors_preflight(config.allow_cors_preflight)
+    .with_original_method(config.trust_original_method))
+}
+
+fn load_client_secret(config: &Config) -> Result<String> {
+    Ok(match &config.client_secret {
+        Some(ClientSecret::String { client_secret }) => client_secret.expose().to_owned(),
+        Some(ClientSecret::File { client_secret_file }) => fs::read_to_string(client_secret_file)?,
+        None => match credential_path() {
+            Some(path) => fs::read_to_string(path)?,
+            None => bail!("either CLIENT_SECRET or CLIENT_SECRET_FILE must be set"),
+        },
+    })
+}
+
+/// Path of the client secret if passed as a systemd credential (e.g. via `LoadCredential=`).
+fn credential_path() -> Option<PathBuf> {
+    env::var_os("CREDENTIALS_DIRECTORY").map(|dir| PathBuf::from(dir).join("client_secret"))
+}
+
+fn load_cipher(config: &Config) -> Result<Option<Cipher>> {
+    let mut encryption_keys: Vec<_> = config
+        .session_encryption_keys
+        .iter()
+        .map(|key| key.expose().to_owned())
+        .collect();
+    if let Some(path) = &config.session_encryption_keys_file {
+        encryption_keys.extend(
+            fs::read_to_string(path)?
+                .split_whitespace()
+                .map(ToOwned::to_owned),
+        );
+    }
+    Ok(if encryption_keys.is_empty() {
+        warn!("no session encryption keys configured, tokens will be stored in plaintext");
+        None
+    } else {
+        Some(Cipher::new(&encryption_keys)?)
+    })
+}
+
+/// Files the configuration and secrets are read from.
+fn watched_paths(config: &Config) -> Vec<PathBuf> {
+    let client_secret_file = match &config.client_secret {
+        Some(ClientSecret::String { .. }) => None,
+        Some(ClientSecret::File { client_secret_file }) => Some(client_secret_file.clone()),
+        None => credential_path(),
+    };
+    [
+        Some(config::path().into()),
+        client_secret_file,
+        config.policy_file.clone(),
+        config.session_encryption_keys_file.clone(),
+        config.http_ca_file.clone(),
+        config.http_client_cert_file.clone(),
+        config.http_client_key_file.clone(),
+    ]
+    .into_iter()
+    .flatten()
+    .collect()
+}
+
+/// Polls the modification times of a set of files.
+struct WatchedFiles {
+    paths: Vec<PathBuf>,
+    modified: Vec<Option<SystemTime>>,
+}
+
+impl WatchedFiles {
+    fn new(paths: Vec<PathBuf>) -> Self {
+        let modified = modification_times(&paths);
+        Self { paths, modified }
+    }
+
+    /// Resolve once any of the files has been modified, created or removed. Never resolves if
+    /// `interval` is `None`.
+    async fn changed(&mut self, interval: Option<Duration>) {
+        let Some(interval) = interval else {
+            return std::future::pending().await;
+        };
+        loop {
+            tokio::time::sleep(interval).await;
+            let modified = modification_times(&self.paths);
+            if modified != self.modified {
+                self.modified = modified;
+                return;
+            }
+        }
+    }
+}
+
+fn modification_times(paths: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    paths
+        .iter()
+        // follows symlinks, so secrets that are swapped atomically (e.g. in kubernetes) are detected
+        .map(|path| fs::metadata(path).and_then(|meta| meta.modified()).ok())
+        .collect()
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_watched_files() {
+        let path = env::temp_dir().join(format!("nginx-keycloak-reload-{}", std::process::id()));
+        fs::write(&path, "secret").unwrap();
+
+        let mut files = WatchedFiles::new(vec![path.clone()]);
+        let interval = Some(Duration::from_millis(10));
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), files.changed(interval))
+                .await
+                .is_err()
+        );
+
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(1))
+            .unwrap();
+        assert!(
+            tokio::time::timeout(Duration::from_secs(1), files.changed(interval))
+                .await
+                .is_ok()
+        );
+
+        fs::remove_file(&path).unwrap();
+        assert!(
+            tokio::time::timeout(Duration::from_secs(1), files.changed(interval))
+                .await
+                .is_ok()
+        );
+    }
+}
@@ -0,0 +1,41 @@
+use eyre::Result;
+use sd_notify::NotifyState;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+};
+use tracing::{info, warn};
+
+/// Resolves once a shutdown has been requested via SIGTERM or SIGINT.
+#[derive(Clone)]
+pub struct Shutdown(watch::Receiver<bool>);
+
+impl Shutdown {
+    pub fn on_signal() -> Result<Self> {
+        let mut sigterm = signal(SignalKind::terminate())?;
+        let mut sigint = signal(SignalKind::interrupt())?;
+        let (sender, receiver) = watch::channel(false);
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = sigterm.recv() => info!("received SIGTERM, shutting down"),
+                _ = sigint.recv() => info!("received SIGINT, shutting down"),
+            }
+            notify(NotifyState::Stopping);
+            // the receivers only disappear once all servers have stopped
+            let _ = sender.send(true);
+        });
+        Ok(Self(receiver))
+    }
+
+    pub async fn wait(mut self) {
+        // an error means the sender is gone, which only happens after a shutdown has been requested
+        let _ = self.0.wait_for(|shutdown| *shutdown).await;
+    }
+}
+
+/// Notify systemd about a state change of the service (if running as a `Type=notify` unit).
+pub fn notify(state: NotifyState) {
+    if let Err(err) = sd_notify::notify(false, &[state]) {
+        warn!("could not notify systemd: {err}");
+    }
+}